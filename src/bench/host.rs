@@ -0,0 +1,55 @@
+// The code of the proc macro generated by `cargo watt bench`. This is not a module of cargo watt,
+// it's appended to the prelude of the host (see `host/prelude.rs`), followed by the `wasm` and `native` fns calling the macros.
+
+use std::time::{Duration, Instant};
+
+/// How long a macro is run on a case to get the average time of an expansion.
+const MEASURE_TIME: Duration = Duration::from_millis(200);
+const MAX_ITERATIONS: u32 = 10_000;
+
+/// Expects the cases as `[name (args) (input)]` and writes the timings to `$CARGO_WATT_HOST_RESULTS`.
+#[proc_macro]
+pub fn run(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let cases: Vec<(String, TokenStream, TokenStream)> = invocations(tokens)
+        .iter()
+        .map(|parts| {
+            let name = parts[0].to_string();
+            (name, group_stream(parts.get(1)), group_stream(parts.get(2)))
+        })
+        .collect();
+
+    // the first call parses the wasm module, which happens once per compiler process
+    let first_call = cases.first().map(|(name, args, input)| {
+        let start = Instant::now();
+        let _ = catch(|| wasm(name, args.clone(), input.clone()));
+        start.elapsed()
+    });
+
+    let mut results = Vec::new();
+    for (name, args, input) in &cases {
+        let wasm = measure(|| wasm(name, args.clone(), input.clone()));
+        let native = measure(|| native(name, args.clone(), input.clone()));
+        results.push(serde_json::json!({
+            "wasm_ns": wasm.map(|time| time.as_nanos() as u64),
+            "native_ns": native.map(|time| time.as_nanos() as u64),
+        }));
+    }
+
+    write_results(serde_json::json!({
+        "first_call_ns": first_call.map(|time| time.as_nanos() as u64),
+        "cases": results,
+    }))
+}
+
+/// The average time of a call to `f`, or `None` if it panics.
+fn measure(f: impl Fn() -> TokenStream) -> Option<Duration> {
+    catch(&f).ok()?;
+
+    let start = Instant::now();
+    let mut iterations = 0;
+    while iterations < MAX_ITERATIONS && start.elapsed() < MEASURE_TIME {
+        f();
+        iterations += 1;
+    }
+    Some(start.elapsed() / iterations)
+}
@@ -0,0 +1,317 @@
+//! `cargo watt bench` measures whether replacing a proc macro crate with its shim pays off:
+//! the shim saves compiling the crate and its dependencies, but the expansions run in the wasm interpreter.
+//! The expansions are timed in the host of the difftest, with the template in `host.rs` instead,
+//! and both are built with the dev profile, since that's what proc macros are compiled with by default.
+//! With `--project`, a project patched by `cargo watt patch` is built from scratch with and without the patches.
+
+use crate::{
+    build,
+    difftest::{self, corpus},
+    host, patch,
+    report::ErrorCode,
+    utils::{self, human_duration},
+    wasm::{self, modifications},
+    CompilationOptions,
+};
+use anyhow::Context;
+use cargo_metadata::{CargoOpt, MetadataCommand};
+use serde_json::Value;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+const HOST: &str = include_str!("host.rs");
+
+/// The time spent in the expansions of all cases which didn't panic.
+struct Expansions {
+    /// the first call of the wasm module minus its expansion
+    load: Duration,
+    native: Duration,
+    wasm: Duration,
+    panicked: usize,
+}
+
+/// Benchmarks the crate in `directory`, on the invocations in `consumer` or else in its own tests and examples.
+/// The wasm module is compiled from the crate, unless a `wasm_file` is given.
+pub fn bench(
+    directory: &Path,
+    compilation_options: &CompilationOptions,
+    wasm_file: Option<&Path>,
+    consumer: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let manifest = utils::parse_validate_toml(&directory.join("Cargo.toml"))?;
+    let name = manifest["package"]["name"].as_str().unwrap().to_string();
+    let scratch = utils::Tempdir::new().context("failed to create temporary directory")?;
+
+    // the wasm module is compiled from `directory` in place, so everything else happens on copies
+    log::info!("building '{}' natively...", name);
+    let native_build = scratch.join("native");
+    utils::copy_all(directory, &native_build)?;
+    let native_time = time_build(&native_build, &scratch.join("target/native"), &["--lib"])?;
+
+    let host = scratch.join("host");
+    std::fs::create_dir_all(&host)?;
+    utils::copy_all(directory, &host.join("native"))?;
+    let fns = modifications::make_modifications(&host.join("native"), &name.replace('-', "_"))
+        .context(ErrorCode::ModificationFailed)?;
+    modifications::make_rlib(&host.join("native/Cargo.toml"))?;
+    modifications::make_native(&host.join("native"))?;
+
+    let paths: Vec<PathBuf> = match consumer {
+        Some(consumer) => ["src", "tests", "examples", "benches"]
+            .iter()
+            .map(|dir| consumer.join(dir))
+            .collect(),
+        None => vec![directory.join("tests"), directory.join("examples")],
+    };
+    let base = consumer.unwrap_or(directory);
+    let cases: Vec<corpus::Case> = corpus::rust_files(&paths)
+        .iter()
+        .flat_map(|file| corpus::cases(file, base, &fns))
+        .collect();
+    if cases.is_empty() {
+        anyhow::bail!(
+            "found no invocations of the macros of '{}', pass a crate using them with --consumer",
+            name
+        );
+    }
+
+    let (wasm, compress) = match wasm_file {
+        Some(file) => (
+            std::fs::read(file).with_context(|| format!("cannot read '{}'", file.display()))?,
+            file.extension().is_some_and(|e| e == "deflate"),
+        ),
+        None => {
+            let (_, wasm) = wasm::compile(directory, &manifest, compilation_options, None, false)?;
+            (wasm, compilation_options.compress)
+        }
+    };
+
+    log::info!("building the shim...");
+    let shim = scratch.join("shim");
+    build::write_watt_crate(manifest, &wasm, &fns, &shim, compress)?;
+    let shim_time = time_build(&shim, &scratch.join("target/shim"), &[])?;
+
+    log::info!("timing {} invocations...", cases.len());
+    difftest::write_host(&host, &name, &fns, &cases, &wasm, compress, HOST)?;
+    let interpreted = host::run(&host, "bench", false, |command| {
+        command.env_remove("WATT_JIT");
+    })?;
+    let interpreted = expansions(&interpreted);
+    // watt uses the JIT if it's built with `$WATT_JIT`
+    let jit = match std::env::var_os("WATT_JIT") {
+        Some(_) => Some(expansions(&host::run(&host, "bench-jit", false, |_| {})?)),
+        None => None,
+    };
+
+    println!("compile time of a clean debug build:");
+    println!(
+        "  {} and its dependencies: {}",
+        name,
+        human_duration(native_time)
+    );
+    println!("  the shim, including watt: {}", human_duration(shim_time));
+    println!();
+
+    let source = match consumer {
+        Some(consumer) => format!("in '{}'", consumer.display()),
+        None => "in the tests and examples of the crate".to_string(),
+    };
+    println!(
+        "expansion time of {} invocations {}:",
+        cases.len() - interpreted.panicked,
+        source
+    );
+    println!(
+        "  native:            {}",
+        human_duration(interpreted.native)
+    );
+    let print_wasm = |label: &str, expansions: &Expansions| {
+        println!(
+            "  {} {}, and {} to load the module",
+            label,
+            human_duration(expansions.wasm),
+            human_duration(expansions.load)
+        );
+    };
+    print_wasm("wasm, interpreted:", &interpreted);
+    if let Some(jit) = &jit {
+        print_wasm("wasm, JIT:        ", jit);
+    }
+    if interpreted.panicked > 0 {
+        println!(
+            "  ({} invocations panicked and are left out)",
+            interpreted.panicked
+        );
+    }
+    println!();
+
+    let fastest = jit.as_ref().unwrap_or(&interpreted);
+    let saved = native_time.as_secs_f64() - shim_time.as_secs_f64();
+    let cost = (fastest.load + fastest.wasm).as_secs_f64() - fastest.native.as_secs_f64();
+    if saved <= 0.0 {
+        println!("the shim takes longer to compile than the crate, so patching doesn't pay off");
+    } else {
+        let verdict = if saved > cost {
+            "it pays off"
+        } else {
+            "it doesn't pay off"
+        };
+        println!(
+            "patching saves {:.2}s per clean build and costs {:.2}s whenever the invocations are expanded again, so {}",
+            saved, cost, verdict
+        );
+    }
+    Ok(())
+}
+
+/// Builds the project in `path`, which is already patched, from scratch with and without the patches.
+pub fn project(path: &Path) -> Result<(), anyhow::Error> {
+    let metadata = MetadataCommand::new()
+        .current_dir(path)
+        .exec()
+        .context(ErrorCode::InvalidManifest)?;
+    let toml_path = metadata.workspace_root.join("Cargo.toml");
+    let manifest = crate::utils_toml::read(&toml_path).context(ErrorCode::InvalidManifest)?;
+    if patch::patched_entries(&manifest).is_empty() {
+        anyhow::bail!(
+            "'{}' has no patches, run `cargo watt patch` first",
+            metadata.workspace_root.display()
+        );
+    }
+
+    let scratch = utils::Tempdir::new().context("failed to create temporary directory")?;
+    let patched = scratch.join("patched");
+    let unpatched = scratch.join("unpatched");
+    patch::copy_project(&metadata, &patched)?;
+    patch::copy_project(&metadata, &unpatched)?;
+    patch::unpatch_copy(&metadata, &unpatched, &[])?;
+
+    let without = dependencies(&unpatched)?;
+    let with = dependencies(&patched)?;
+
+    log::info!("building the project without the patches...");
+    let unpatched_time = time_build(
+        &unpatched,
+        &scratch.join("target/unpatched"),
+        &["--workspace"],
+    )?;
+    log::info!("building the project with the patches...");
+    let patched_time = time_build(&patched, &scratch.join("target/patched"), &["--workspace"])?;
+
+    println!("clean debug build of the workspace:");
+    println!("  without the patches: {}", human_duration(unpatched_time));
+    println!("  with the patches:    {}", human_duration(patched_time));
+    let saved = unpatched_time.as_secs_f64() - patched_time.as_secs_f64();
+    if saved >= 0.0 {
+        println!(
+            "the patches save {:.2}s ({:.0}%)",
+            saved,
+            100.0 * saved / unpatched_time.as_secs_f64()
+        );
+    } else {
+        println!("the patches make the build {:.2}s slower", -saved);
+    }
+
+    let list = |packages: Vec<&String>| match packages.is_empty() {
+        true => "none".to_string(),
+        false => packages.into_iter().cloned().collect::<Vec<_>>().join(", "),
+    };
+    println!();
+    println!(
+        "crates which leave the dependency graph: {}",
+        list(without.difference(&with).collect())
+    );
+    println!(
+        "crates which join it: {}",
+        list(with.difference(&without).collect())
+    );
+    Ok(())
+}
+
+/// The packages the project in `root` depends on, as `<name> <version>`.
+fn dependencies(root: &Path) -> Result<BTreeSet<String>, anyhow::Error> {
+    let metadata = MetadataCommand::new()
+        .current_dir(root)
+        .features(CargoOpt::AllFeatures)
+        .exec()
+        .context(ErrorCode::InvalidManifest)?;
+    Ok(metadata
+        .packages
+        .iter()
+        .filter(|package| !metadata.workspace_members.contains(&package.id))
+        .map(|package| format!("{} {}", package.name, package.version))
+        .collect())
+}
+
+/// Builds the crate in `directory` from scratch in `target_dir` and returns how long that took.
+/// The dependencies are fetched first, so that downloading them isn't measured.
+fn time_build(
+    directory: &Path,
+    target_dir: &Path,
+    args: &[&str],
+) -> Result<Duration, anyhow::Error> {
+    let cargo = |command: &str, args: &[&str]| {
+        Command::new("cargo")
+            .arg(command)
+            .args(args)
+            .env("CARGO_TARGET_DIR", target_dir)
+            .current_dir(directory)
+            .output()
+            .context("failed to run cargo")
+    };
+
+    let output = cargo("fetch", &[])?;
+    if !output.status.success() {
+        let error = anyhow::anyhow!(
+            "failed to fetch the dependencies:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(error.context(ErrorCode::FetchFailed));
+    }
+
+    let start = Instant::now();
+    let output = cargo("build", args)?;
+    let duration = start.elapsed();
+    if !output.status.success() {
+        let error = anyhow::anyhow!(
+            "failed to build '{}':\n{}",
+            directory.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(error.context(ErrorCode::CompileFailed));
+    }
+    Ok(duration)
+}
+
+fn expansions(results: &Value) -> Expansions {
+    let nanos = |value: &Value| value.as_u64().map(Duration::from_nanos);
+    let mut expansions = Expansions {
+        load: Duration::ZERO,
+        native: Duration::ZERO,
+        wasm: Duration::ZERO,
+        panicked: 0,
+    };
+    let cases = results["cases"].as_array().map_or(&[][..], Vec::as_slice);
+    for case in cases {
+        match (nanos(&case["wasm_ns"]), nanos(&case["native_ns"])) {
+            (Some(wasm), Some(native)) => {
+                expansions.wasm += wasm;
+                expansions.native += native;
+            }
+            _ => expansions.panicked += 1,
+        }
+    }
+
+    // the first call also expanded the first case
+    if let (Some(first_call), Some(first_case)) = (
+        nanos(&results["first_call_ns"]),
+        cases.first().and_then(|case| nanos(&case["wasm_ns"])),
+    ) {
+        expansions.load = first_call.saturating_sub(first_case);
+    }
+    expansions
+}
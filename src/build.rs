@@ -1,33 +1,60 @@
 use crate::{
+    patch::bundle,
+    report::{self, Event},
     utils,
-    wasm::{self, ProcMacroFn, ProcMacroKind},
+    wasm::{self, MacroMetadata, ProcMacroFn, ProcMacroKind, TargetDir},
     CompilationOptions,
 };
-use std::path::{Path, PathBuf};
+use anyhow::Context;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// What `cargo watt build` produces.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Emit {
+    /// the shim crate including the wasm file
+    Crate,
+    /// only the wasm file, `cargo watt shim` generates the crate from it
+    Wasm,
+}
+impl FromStr for Emit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crate" => Ok(Emit::Crate),
+            "wasm" => Ok(Emit::Wasm),
+            _ => anyhow::bail!("unknown output '{}', expected 'crate' or 'wasm'", s),
+        }
+    }
+}
 
+/// Compiles the crate in `directory` and generates the watt crate.
+/// Returns the size of the generated wasm file.
 pub fn build(
     directory: &Path,
     out_dir: Option<PathBuf>,
     compilation_options: &CompilationOptions,
     only_copy_essential: bool,
     overwrite: bool,
+    target_dir: Option<&TargetDir>,
     verbose: bool,
-) -> Result<(), anyhow::Error> {
+) -> Result<usize, anyhow::Error> {
     let manifest = utils::parse_validate_toml(&directory.join("Cargo.toml"))?;
     let name = manifest["package"]["name"].as_str().unwrap().to_string();
 
     let crate_path = out_dir.unwrap_or_else(|| PathBuf::from(format!("{}-watt", name)));
+    prepare_out_path(&crate_path, overwrite)?;
 
-    match (crate_path.exists(), overwrite) {
-        (true, false) => anyhow::bail!(
-            "'{}' already exists. Use --overwrite to overwrite.",
-            crate_path.display()
-        ),
-        (true, true) => std::fs::remove_dir_all(&crate_path)?,
-        (false, _) => {}
-    }
-
-    let (fns, wasm) = wasm::compile(directory, &manifest, compilation_options, verbose)?;
+    let (fns, wasm) = wasm::compile(
+        directory,
+        &manifest,
+        compilation_options,
+        target_dir,
+        verbose,
+    )?;
 
     create_watt_crate(
         manifest,
@@ -39,11 +66,89 @@ pub fn build(
         compilation_options.compress,
     )?;
 
+    Ok(wasm.len())
+}
+
+/// Compiles the crate in `directory` to a wasm file at `out_file`, without generating a crate around it.
+pub fn emit_wasm(
+    directory: &Path,
+    out_file: Option<PathBuf>,
+    compilation_options: &CompilationOptions,
+    overwrite: bool,
+) -> Result<(), anyhow::Error> {
+    let manifest = utils::parse_validate_toml(&directory.join("Cargo.toml"))?;
+    let name = manifest["package"]["name"].as_str().unwrap().to_string();
+
+    let file_name = wasm_file_name(&name, compilation_options.compress);
+    let out_file = out_file.unwrap_or_else(|| PathBuf::from(file_name));
+    prepare_out_path(&out_file, overwrite)?;
+
+    let (_, wasm) = wasm::compile(directory, &manifest, compilation_options, None, false)?;
+    std::fs::write(&out_file, wasm)?;
+    log::info!("generated wasm file {:?}", out_file);
+    Ok(())
+}
+
+/// Generates the shim crate for the wasm file `wasm_file` from the metadata embedded in it.
+pub fn shim(
+    wasm_file: &Path,
+    out_dir: Option<PathBuf>,
+    overwrite: bool,
+) -> Result<(), anyhow::Error> {
+    let compress = wasm_file.extension().is_some_and(|e| e == "deflate");
+    let wasm = wasm::read_wasm_file(wasm_file)?;
+    let metadata = MacroMetadata::read(&wasm)?.with_context(|| {
+        format!(
+            "'{}' contains no '{}' section, it wasn't built with `cargo watt build`",
+            wasm_file.display(),
+            wasm::metadata::SECTION
+        )
+    })?;
+
+    // the macros of a bundle come from several crates, each of which has a facade of its own
+    if metadata.crate_ == bundle::RUNTIME_CRATE {
+        anyhow::bail!(
+            "'{}' is the module of a bundle, its shims are generated by `cargo watt bundle`",
+            wasm_file.display()
+        );
+    }
+
+    let crate_path = out_dir.unwrap_or_else(|| PathBuf::from(format!("{}-watt", metadata.crate_)));
+    prepare_out_path(&crate_path, overwrite)?;
+
+    let mut manifest: toml_edit::Document = format!(
+        "[package]\nname = \"{}\"\nversion = \"{}\"\nedition = \"2018\"\n\n[lib]\nproc-macro = true\n\n[dependencies]\n",
+        metadata.crate_, metadata.version
+    )
+    .parse()?;
+    if metadata
+        .fns
+        .iter()
+        .any(|f| f.kind == ProcMacroKind::ProcMacroHack)
+    {
+        manifest["dependencies"]["proc-macro-hack"] = toml_edit::value("0.5");
+    }
+
+    // the file is copied as is, so a compressed one stays compressed
+    let content = std::fs::read(wasm_file)?;
+    write_watt_crate(manifest, &content, &metadata.fns, &crate_path, compress)
+}
+
+fn prepare_out_path(path: &Path, overwrite: bool) -> Result<(), anyhow::Error> {
+    match (path.exists(), overwrite) {
+        (true, false) => anyhow::bail!(
+            "'{}' already exists. Use --overwrite to overwrite.",
+            path.display()
+        ),
+        (true, true) if path.is_dir() => std::fs::remove_dir_all(path)?,
+        (true, true) => std::fs::remove_file(path)?,
+        (false, _) => {}
+    }
     Ok(())
 }
 
 // Replaces the [dependency] section with a `watt = "0.4"` dependency
-fn modify_cargo_toml_for_watt(manifest: &mut toml_edit::Document, compress: bool) {
+pub fn modify_cargo_toml_for_watt(manifest: &mut toml_edit::Document, compress: bool) {
     // if the crate depends on proc-macro-hack, we wanna use it aswell
     let proc_macro_hack = manifest["dependencies"]["proc-macro-hack"].clone();
 
@@ -75,67 +180,220 @@ fn modify_cargo_toml_for_watt(manifest: &mut toml_edit::Document, compress: bool
 }
 
 fn watt_librs(name: &str, fns: &[ProcMacroFn], compress: bool) -> String {
-    let uses_proc_macro_hack = fns.iter().any(|f| f.kind == ProcMacroKind::ProcMacroHack);
-    let use_proc_macro_hack = if uses_proc_macro_hack {
-        Some(quote::quote! { use proc_macro_hack::proc_macro_hack; })
-    } else {
-        None
-    };
+    let statics = wasm_statics(&wasm_file_name(name, compress), compress, false);
+    shim_librs(name, statics, fns, true)
+}
 
+pub fn wasm_file_name(name: &str, compress: bool) -> String {
     let mut file_name = format!("{}.wasm", &name);
     if compress {
         file_name.push_str(".deflate");
     }
+    file_name
+}
 
-    let macro_static = if compress {
+/// The `WASM` and `MACRO` statics loading the wasm file `file_name` next to the `lib.rs`.
+pub fn wasm_statics(file_name: &str, compress: bool, public: bool) -> proc_macro2::TokenStream {
+    let vis = if public {
+        Some(quote::quote!(pub))
+    } else {
+        None
+    };
+    if compress {
         quote::quote! {
             extern crate once_cell;
 
             use once_cell::sync::Lazy;
 
-            static WASM: Lazy<Vec<u8>> = Lazy::new(|| miniz_oxide::inflate::decompress_to_vec(include_bytes!(#file_name)).expect("failed to decomress wasm"));
-            static MACRO: Lazy<watt::WasmMacro> = Lazy::new(|| watt::WasmMacro::new(&WASM));
+            #vis static WASM: Lazy<Vec<u8>> = Lazy::new(|| miniz_oxide::inflate::decompress_to_vec(include_bytes!(#file_name)).expect("failed to decomress wasm"));
+            #vis static MACRO: Lazy<watt::WasmMacro> = Lazy::new(|| watt::WasmMacro::new(&WASM));
         }
     } else {
         quote::quote! {
-            static WASM: &[u8] = include_bytes!(#file_name);
-            static MACRO: watt::WasmMacro = watt::WasmMacro::new(WASM);
+            #vis static WASM: &[u8] = include_bytes!(#file_name);
+            #vis static MACRO: watt::WasmMacro = watt::WasmMacro::new(WASM);
+        }
+    }
+}
+
+/// The environment variable with a wasm file the shim of crate `name` loads instead of its own, see `cargo watt watch`.
+pub fn override_var(name: &str) -> String {
+    format!("WATT_OVERRIDE_{}", name.replace('-', "_").to_uppercase())
+}
+
+/// Where shims write with `var` (`CARGO_WATT_RECORD` or `CARGO_WATT_PROFILE`) set: the absolute path in it,
+/// or for `1` (and if it isn't set) `default` in `target/cargo-watt` of the project in the current directory.
+pub fn shim_output(var: &str, default: &str) -> Result<PathBuf, anyhow::Error> {
+    match std::env::var_os(var) {
+        Some(path) if path != "1" => Ok(PathBuf::from(path)),
+        _ => {
+            let metadata = cargo_metadata::MetadataCommand::new()
+                .no_deps()
+                .exec()
+                .with_context(|| format!("{} isn't set and there is no project here", var))?;
+            Ok(metadata.target_directory.join("cargo-watt").join(default))
         }
+    }
+}
+
+/// The `lib.rs` of the shim of crate `name` with the proc macros `fns`, calling into the `MACRO` brought in by `statics`.
+/// With `overridable`, the shim loads the wasm file in `$WATT_OVERRIDE_<CRATE>` instead if it's set.
+pub fn shim_librs(
+    name: &str,
+    statics: proc_macro2::TokenStream,
+    fns: &[ProcMacroFn],
+    overridable: bool,
+) -> String {
+    let uses_proc_macro_hack = fns.iter().any(|f| f.kind == ProcMacroKind::ProcMacroHack);
+    let use_proc_macro_hack = if uses_proc_macro_hack {
+        Some(quote::quote! { use proc_macro_hack::proc_macro_hack; })
+    } else {
+        None
     };
 
+    let override_var = override_var(name);
+    let watt_macro = if overridable {
+        quote::quote! {
+            /// The wasm file in `$WATT_OVERRIDE_<CRATE>` if it's set, which is read once per thread of the compiler.
+            fn __watt_macro() -> &'static watt::WasmMacro {
+                thread_local! {
+                    static OVERRIDE: Option<&'static watt::WasmMacro> = std::env::var_os(#override_var).map(|path| {
+                        let wasm = std::fs::read(&path).unwrap_or_else(|e| {
+                            panic!("cannot read {}={}: {}", #override_var, path.to_string_lossy(), e)
+                        });
+                        &*Box::leak(Box::new(watt::WasmMacro::new(Box::leak(wasm.into_boxed_slice()))))
+                    });
+                }
+                OVERRIDE.with(|wasm_macro| *wasm_macro).unwrap_or(&MACRO)
+            }
+        }
+    } else {
+        quote::quote! {
+            fn __watt_macro() -> &'static watt::WasmMacro {
+                &MACRO
+            }
+        }
+    };
     let lib = quote::quote! {
-        #macro_static
+        #statics
         #use_proc_macro_hack
 
         #(#fns)*
+
+        #watt_macro
+
+        /// With `$CARGO_WATT_RECORD` set to a directory, writes every invocation into it, see `cargo watt replay`.
+        /// With `$CARGO_WATT_PROFILE` set to a file, appends the time of every expansion to it, see `cargo watt profile-report`.
+        /// `1` stands for `target/cargo-watt/recordings` and `target/cargo-watt/profile.log`.
+        fn __watt_record(
+            export: &str,
+            kind: &str,
+            args: Option<proc_macro::TokenStream>,
+            input: proc_macro::TokenStream,
+            expand: impl FnOnce(Option<proc_macro::TokenStream>, proc_macro::TokenStream) -> proc_macro::TokenStream,
+        ) -> proc_macro::TokenStream {
+            // rustc runs in the directory of the crate it compiles, so relative paths would scatter the files.
+            // The macro runs inside of rustc, whose `--out-dir` is `target/<profile>/deps`.
+            let path = |var: &str, default: &str| {
+                let path = std::path::PathBuf::from(std::env::var_os(var)?);
+                if path.as_os_str() == "1" {
+                    let out_dir = std::env::args().skip_while(|arg| arg != "--out-dir").nth(1)?;
+                    let target = std::path::Path::new(&out_dir).parent()?.parent()?;
+                    return Some(target.join("cargo-watt").join(default));
+                }
+                if !path.is_absolute() {
+                    panic!("{} needs to be 1 or an absolute path, not {}", var, path.display());
+                }
+                Some(path)
+            };
+            let record = path("CARGO_WATT_RECORD", "recordings");
+            let profile = path("CARGO_WATT_PROFILE", "profile.log");
+            if record.is_none() && profile.is_none() {
+                return expand(args, input);
+            }
+            let args_string = args.as_ref().map(ToString::to_string).unwrap_or_default();
+            let input_string = input.to_string();
+            let start = std::time::Instant::now();
+            let output = expand(args, input);
+            let elapsed = start.elapsed();
+
+            if let Some(log) = profile {
+                use std::io::Write;
+                // a single write per line, since the macros of several crates are expanded at once
+                let line = format!("{}\t{}\t{}\n", export, input_string.len(), elapsed.as_nanos());
+                let _ = log
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| {
+                        std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(&log)
+                    })
+                    .and_then(|mut file| file.write_all(line.as_bytes()));
+            }
+            if let Some(dir) = record {
+                // FNV-1a, which unlike `DefaultHasher` is the same for every Rust release
+                let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+                for part in &[kind, &args_string, &input_string] {
+                    for byte in part.bytes().chain(Some(0)) {
+                        hash = (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3);
+                    }
+                }
+                let dir = dir.join(export).join(format!("{:016x}", hash));
+                let _ = std::fs::create_dir_all(&dir)
+                    .and_then(|_| std::fs::write(dir.join("kind"), kind))
+                    .and_then(|_| std::fs::write(dir.join("args"), &args_string))
+                    .and_then(|_| std::fs::write(dir.join("input"), &input_string))
+                    .and_then(|_| std::fs::write(dir.join("output"), output.to_string()));
+            }
+            output
+        }
     };
 
     lib.to_string()
 }
 
 fn create_watt_crate(
-    mut manifest: toml_edit::Document,
+    manifest: toml_edit::Document,
     wasm: &[u8],
     fns: Vec<ProcMacroFn>,
     crate_path: &Path,
     tmp_directory: &Path,
     only_copy_essential: bool,
     compress: bool,
+) -> Result<(), anyhow::Error> {
+    if !only_copy_essential {
+        utils::copy_all(tmp_directory, crate_path)?;
+        std::fs::remove_file(crate_path.join("Cargo.lock"))?;
+        std::fs::remove_dir_all(crate_path.join("src"))?;
+    }
+
+    std::fs::create_dir_all(crate_path)?;
+    std::fs::rename(
+        tmp_directory.join("Cargo.lock"),
+        crate_path.join("Cargo.watt.lock"),
+    )?;
+
+    write_watt_crate(manifest, wasm, &fns, crate_path, compress)
+}
+
+/// Writes the `Cargo.toml`, `lib.rs` and the wasm file of the shim crate.
+pub fn write_watt_crate(
+    mut manifest: toml_edit::Document,
+    wasm: &[u8],
+    fns: &[ProcMacroFn],
+    crate_path: &Path,
+    compress: bool,
 ) -> Result<(), anyhow::Error> {
     let name = manifest["package"]["name"].as_str().unwrap().to_string();
 
     modify_cargo_toml_for_watt(&mut manifest, compress);
     let new_toml = manifest.to_string_in_original_order();
-    let lib = watt_librs(&name, &fns, compress);
+    let lib = watt_librs(&name, fns, compress);
 
     let src = crate_path.join("src");
 
-    if !only_copy_essential {
-        utils::copy_all(tmp_directory, &crate_path)?;
-        std::fs::remove_file(crate_path.join("Cargo.lock"))?;
-        std::fs::remove_dir_all(&src)?;
-    }
-
     let mut wasm_file = src.join(&name).with_extension("wasm");
     if compress {
         wasm_file.set_extension("wasm.deflate");
@@ -144,16 +402,15 @@ fn create_watt_crate(
     std::fs::create_dir_all(&src)?;
     std::fs::write(crate_path.join("Cargo.toml"), new_toml)?;
     std::fs::write(wasm_file, wasm)?;
-    std::fs::write(src.join("lib.rs"), lib.to_string())?;
-
-    std::fs::rename(
-        tmp_directory.join("Cargo.lock"),
-        crate_path.join("Cargo.watt.lock"),
-    )?;
+    std::fs::write(src.join("lib.rs"), lib)?;
 
     log::info!("generated crate in {:?}", crate_path);
+    report::emit(Event::CrateGenerated {
+        crate_: &name,
+        path: crate_path,
+    });
 
-    if let Err(e) = utils::cargo_fmt(&crate_path) {
+    if let Err(e) = utils::cargo_fmt(crate_path) {
         log::warn!("failed to format crate: {}", e);
     }
 
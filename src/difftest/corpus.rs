@@ -0,0 +1,301 @@
+//! Finds the invocations of the macros of a crate in Rust files, to be used as inputs for the difftest.
+
+use crate::wasm::{ProcMacroFn, ProcMacroKind};
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use std::path::{Path, PathBuf};
+use syn::visit::Visit;
+
+pub struct Case {
+    /// the file and the number of the case in it
+    pub label: String,
+    /// the fn of the macro
+    pub name: syn::Ident,
+    pub args: TokenStream,
+    pub input: TokenStream,
+}
+
+/// The `.rs` files in `paths`, which are either files or directories to search.
+pub fn rust_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .flat_map(walkdir::WalkDir::new)
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .map(walkdir::DirEntry::into_path)
+        .filter(|path| path.extension().is_some_and(|e| e == "rs"))
+        .collect()
+}
+
+/// The invocations of `fns` in `file`, which is labeled relative to `base`.
+pub fn cases(file: &Path, base: &Path, fns: &[ProcMacroFn]) -> Vec<Case> {
+    let label = file
+        .strip_prefix(base)
+        .unwrap_or(file)
+        .display()
+        .to_string();
+    let parsed = match std::fs::read_to_string(file)
+        .map_err(anyhow::Error::from)
+        .and_then(|source| Ok(syn::parse_file(&source)?))
+    {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::debug!("skipping {}: {}", label, e);
+            return Vec::new();
+        }
+    };
+
+    let mut visitor = Visitor {
+        label,
+        fns,
+        cases: Vec::new(),
+    };
+    visitor.visit_file(&parsed);
+    visitor.cases
+}
+
+/// The name used in `#[derive(..)]` for a derive macro.
+fn derive_name(f: &ProcMacroFn) -> Option<syn::Ident> {
+    f.attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("proc_macro_derive"))
+        .find_map(|attr| match attr.parse_meta().ok()? {
+            syn::Meta::List(list) => match list.nested.first()? {
+                syn::NestedMeta::Meta(meta) => meta.path().get_ident().cloned(),
+                _ => None,
+            },
+            _ => None,
+        })
+}
+
+struct Visitor<'a> {
+    label: String,
+    fns: &'a [ProcMacroFn],
+    cases: Vec<Case>,
+}
+
+impl<'a> Visitor<'a> {
+    fn push(&mut self, name: &syn::Ident, args: TokenStream, input: TokenStream) {
+        self.cases.push(Case {
+            label: format!("{}#{}", self.label, self.cases.len() + 1),
+            name: name.clone(),
+            args,
+            input,
+        });
+    }
+
+    fn find(&self, kinds: &[ProcMacroKind], path: &syn::Path) -> Option<&'a ProcMacroFn> {
+        let last = &path.segments.last()?.ident;
+        self.fns.iter().find(|f| {
+            kinds.contains(&f.kind)
+                && match f.kind {
+                    ProcMacroKind::Derive => derive_name(f).is_some_and(|name| &name == last),
+                    _ => &f.name == last,
+                }
+        })
+    }
+}
+
+impl<'ast> Visit<'ast> for Visitor<'_> {
+    fn visit_item(&mut self, item: &'ast syn::Item) {
+        let attrs = item_attrs(item);
+
+        // derive macros get the item without the `#[derive]` attributes
+        let derives: Vec<syn::Path> = attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("derive"))
+            .filter_map(|attr| match attr.parse_meta().ok()? {
+                syn::Meta::List(list) => Some(list.nested),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|nested| match nested {
+                syn::NestedMeta::Meta(meta) => Some(meta.path().clone()),
+                _ => None,
+            })
+            .collect();
+        for path in derives {
+            if let Some(f) = self.find(&[ProcMacroKind::Derive], &path) {
+                let input = without_attrs(item, |attr| attr.path.is_ident("derive"));
+                self.push(&f.name, TokenStream::new(), input);
+            }
+        }
+
+        // attribute macros get the item without their own attribute
+        for (i, attr) in attrs.iter().enumerate() {
+            if let Some(f) = self.find(&[ProcMacroKind::Attribute], &attr.path) {
+                let args = match attr.tokens.clone().into_iter().next() {
+                    Some(TokenTree::Group(group)) => group.stream(),
+                    _ => TokenStream::new(),
+                };
+                let mut index = 0;
+                let input = without_attrs(item, |_| {
+                    index += 1;
+                    index - 1 == i
+                });
+                self.push(&f.name, args, input);
+            }
+        }
+
+        syn::visit::visit_item(self, item);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        let kinds = [ProcMacroKind::Macro, ProcMacroKind::ProcMacroHack];
+        if let Some(f) = self.find(&kinds, &mac.path) {
+            self.push(&f.name, TokenStream::new(), mac.tokens.clone());
+        }
+        syn::visit::visit_macro(self, mac);
+    }
+}
+
+fn item_attrs(item: &syn::Item) -> &[syn::Attribute] {
+    match item {
+        syn::Item::Const(item) => &item.attrs,
+        syn::Item::Enum(item) => &item.attrs,
+        syn::Item::Fn(item) => &item.attrs,
+        syn::Item::Impl(item) => &item.attrs,
+        syn::Item::Mod(item) => &item.attrs,
+        syn::Item::Static(item) => &item.attrs,
+        syn::Item::Struct(item) => &item.attrs,
+        syn::Item::Trait(item) => &item.attrs,
+        syn::Item::Type(item) => &item.attrs,
+        syn::Item::Union(item) => &item.attrs,
+        _ => &[],
+    }
+}
+
+/// The tokens of `item` without the attributes matching `remove`.
+fn without_attrs(item: &syn::Item, mut remove: impl FnMut(&syn::Attribute) -> bool) -> TokenStream {
+    let mut item = item.clone();
+    let attrs = match &mut item {
+        syn::Item::Const(item) => &mut item.attrs,
+        syn::Item::Enum(item) => &mut item.attrs,
+        syn::Item::Fn(item) => &mut item.attrs,
+        syn::Item::Impl(item) => &mut item.attrs,
+        syn::Item::Mod(item) => &mut item.attrs,
+        syn::Item::Static(item) => &mut item.attrs,
+        syn::Item::Struct(item) => &mut item.attrs,
+        syn::Item::Trait(item) => &mut item.attrs,
+        syn::Item::Type(item) => &mut item.attrs,
+        syn::Item::Union(item) => &mut item.attrs,
+        _ => return item.into_token_stream(),
+    };
+    attrs.retain(|attr| !remove(attr));
+    item.into_token_stream()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proc_macro_fn(item: syn::ItemFn, kind: ProcMacroKind) -> ProcMacroFn {
+        ProcMacroFn {
+            name: item.sig.ident,
+            export: String::new(),
+            attrs: item.attrs,
+            kind,
+        }
+    }
+
+    fn fns() -> Vec<ProcMacroFn> {
+        vec![
+            proc_macro_fn(
+                syn::parse_quote! {
+                    #[proc_macro_derive(MyDebug, attributes(debug))]
+                    pub fn derive_my_debug(input: TokenStream) -> TokenStream {}
+                },
+                ProcMacroKind::Derive,
+            ),
+            proc_macro_fn(
+                syn::parse_quote! {
+                    #[proc_macro_attribute]
+                    pub fn route(args: TokenStream, input: TokenStream) -> TokenStream {}
+                },
+                ProcMacroKind::Attribute,
+            ),
+            proc_macro_fn(
+                syn::parse_quote! {
+                    #[proc_macro]
+                    pub fn html(input: TokenStream) -> TokenStream {}
+                },
+                ProcMacroKind::Macro,
+            ),
+        ]
+    }
+
+    fn cases_in(source: &str) -> Vec<(String, String, String, String)> {
+        let fns = fns();
+        let mut visitor = Visitor {
+            label: "tests/t.rs".to_string(),
+            fns: &fns,
+            cases: Vec::new(),
+        };
+        visitor.visit_file(&syn::parse_file(source).unwrap());
+        visitor
+            .cases
+            .into_iter()
+            .map(|case| {
+                (
+                    case.label,
+                    case.name.to_string(),
+                    case.args.to_string(),
+                    case.input.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn case(label: &str, name: &str, args: &str, input: &str) -> (String, String, String, String) {
+        (
+            label.to_string(),
+            name.to_string(),
+            args.to_string(),
+            input.to_string(),
+        )
+    }
+
+    #[test]
+    fn derive_invocations() {
+        let cases = cases_in(
+            "#[derive(Clone, my_macros::MyDebug)]\n#[debug(skip)]\nstruct S { x: u8 }\n#[derive(Debug)]\nenum E {}",
+        );
+        assert_eq!(
+            cases,
+            [case(
+                "tests/t.rs#1",
+                "derive_my_debug",
+                "",
+                "# [debug (skip)] struct S { x : u8 }"
+            )]
+        );
+    }
+
+    #[test]
+    fn attribute_invocations() {
+        let cases = cases_in("#[inline]\n#[route(GET, \"/\")]\n#[cold]\nfn handler() {}");
+        assert_eq!(
+            cases,
+            [case(
+                "tests/t.rs#1",
+                "route",
+                "GET , \"/\"",
+                "# [inline] # [cold] fn handler () { }"
+            )]
+        );
+    }
+
+    #[test]
+    fn function_like_invocations() {
+        let cases = cases_in(
+            "html! { <p/> }\nmod m {\n    fn f() {\n        let x = my_macros::html!(<div/>);\n    }\n}",
+        );
+        assert_eq!(
+            cases,
+            [
+                case("tests/t.rs#1", "html", "", "< p />"),
+                case("tests/t.rs#2", "html", "", "< div />"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,71 @@
+// The code of the proc macro generated by `cargo watt difftest`. This is not a module of cargo watt,
+// it's appended to the prelude of the host (see `host/prelude.rs`), followed by the `wasm` and `native` fns calling the macros.
+
+use reduce::reductions;
+
+/// The number of expansions spent on minimizing a case.
+const MINIMIZE_BUDGET: usize = 500;
+
+/// Expects the cases as `[name (args) (input)]` and writes the results to `$CARGO_WATT_HOST_RESULTS`.
+#[proc_macro]
+pub fn run(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let results = invocations(tokens)
+        .iter()
+        .map(|parts| {
+            let name = parts[0].to_string();
+            test(&name, group_stream(parts.get(1)), group_stream(parts.get(2)))
+        })
+        .collect();
+    write_results(serde_json::Value::Array(results))
+}
+
+fn outcomes(name: &str, args: &TokenStream, input: &TokenStream) -> (Outcome, Outcome) {
+    let wasm = catch(|| wasm(name, args.clone(), input.clone()));
+    let native = catch(|| native(name, args.clone(), input.clone()));
+    (wasm, native)
+}
+
+/// The interpreter doesn't report the panic messages of the macro, so only the fact that both panicked is compared.
+fn differ(outcomes: &(Outcome, Outcome)) -> bool {
+    match outcomes {
+        (Ok(wasm), Ok(native)) => wasm != native,
+        (Err(_), Err(_)) => false,
+        _ => true,
+    }
+}
+
+fn test(name: &str, args: TokenStream, input: TokenStream) -> serde_json::Value {
+    let (wasm, native) = outcomes(name, &args, &input);
+    if !differ(&(wasm.clone(), native.clone())) {
+        return serde_json::json!({ "equal": true });
+    }
+
+    let minimized = minimize(name, &args, input);
+    let (minimized_wasm, minimized_native) = outcomes(name, &args, &minimized);
+    serde_json::json!({
+        "equal": false,
+        "wasm": outcome_json(&wasm),
+        "native": outcome_json(&native),
+        "minimized": {
+            "input": minimized.to_string(),
+            "wasm": outcome_json(&minimized_wasm),
+            "native": outcome_json(&minimized_native),
+        },
+    })
+}
+
+/// Removes token trees from `input` as long as the outputs still differ.
+fn minimize(name: &str, args: &TokenStream, mut input: TokenStream) -> TokenStream {
+    let mut budget = MINIMIZE_BUDGET;
+    'reduce: while budget > 0 {
+        for candidate in reductions(&input).take(budget) {
+            budget -= 1;
+            if differ(&outcomes(name, args, &candidate)) {
+                input = candidate;
+                continue 'reduce;
+            }
+        }
+        break;
+    }
+    input
+}
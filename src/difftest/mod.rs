@@ -0,0 +1,192 @@
+//! `cargo watt difftest` checks that the wasm module behaves like the crate it was compiled from.
+//! The modified crate is also compiled natively, as a library on the regular proc-macro2, and both are run
+//! on the invocations of the macros found in the tests and examples of the crate.
+//! The comparison runs in a generated proc macro (see `host.rs`), since watt only takes input from inside one.
+
+pub mod corpus;
+#[cfg(test)]
+mod reduce;
+
+use crate::{
+    host,
+    report::ErrorCode,
+    utils,
+    wasm::{self, modifications, ProcMacroFn, ProcMacroKind},
+    CompilationOptions,
+};
+use anyhow::Context;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+const HOST: &str = concat!(
+    include_str!("host.rs"),
+    "\nmod reduce {\n",
+    include_str!("reduce.rs"),
+    "}\n"
+);
+
+/// Runs the difftest for the crate in `directory`.
+/// The wasm module is compiled from it, unless a `wasm_file` is given.
+pub fn difftest(
+    directory: &Path,
+    compilation_options: &CompilationOptions,
+    wasm_file: Option<&Path>,
+    corpus: &[PathBuf],
+) -> Result<(), anyhow::Error> {
+    let manifest = utils::parse_validate_toml(&directory.join("Cargo.toml"))?;
+    let name = manifest["package"]["name"].as_str().unwrap().to_string();
+
+    // the wasm module is compiled from `directory` in place, so the native crate is modified on a copy
+    let host = utils::Tempdir::new().context("failed to create temporary directory")?;
+    let native = host.join("native");
+    utils::copy_all(directory, &native)?;
+    let fns = modifications::make_modifications(&native, &name.replace('-', "_"))
+        .context(ErrorCode::ModificationFailed)?;
+    modifications::make_rlib(&native.join("Cargo.toml"))?;
+    modifications::make_native(&native)?;
+
+    let mut paths = vec![directory.join("tests"), directory.join("examples")];
+    paths.extend(corpus.iter().cloned());
+    let cases: Vec<corpus::Case> = corpus::rust_files(&paths)
+        .iter()
+        .flat_map(|file| corpus::cases(file, directory, &fns))
+        .collect();
+    if cases.is_empty() {
+        anyhow::bail!(
+            "found no invocations of the macros of '{}', pass some with --corpus",
+            name
+        );
+    }
+    log::info!(
+        "found {} invocations of the macros of '{}'",
+        cases.len(),
+        name
+    );
+
+    let (wasm, compress) = match wasm_file {
+        Some(file) => (
+            std::fs::read(file).with_context(|| format!("cannot read '{}'", file.display()))?,
+            file.extension().is_some_and(|e| e == "deflate"),
+        ),
+        None => {
+            let (_, wasm) = wasm::compile(directory, &manifest, compilation_options, None, false)?;
+            (wasm, compilation_options.compress)
+        }
+    };
+    write_host(&host, &name, &fns, &cases, &wasm, compress, HOST)?;
+
+    log::info!("running the wasm and the native macros...");
+    let results = host::run(&host, "difftest", true, |_| {})?;
+    report(&cases, results.as_array().unwrap())
+}
+
+/// Writes the host (see `host::write`) running the native crate in `host/native` and the wasm module,
+/// with the `template` and the dispatch to the wasm and native macros in `host-macro`.
+/// The template gets the `cases` as `run! { [name (args) (input)]* }`.
+pub fn write_host(
+    host: &Path,
+    name: &str,
+    fns: &[ProcMacroFn],
+    cases: &[corpus::Case],
+    wasm: &[u8],
+    compress: bool,
+    template: &str,
+) -> Result<(), anyhow::Error> {
+    let names: Vec<String> = fns.iter().map(|f| f.name.to_string()).collect();
+    let wasm_calls = fns.iter().map(|f| {
+        let export = &f.export;
+        match f.kind {
+            ProcMacroKind::Macro | ProcMacroKind::ProcMacroHack => {
+                quote::quote!(MACRO.proc_macro(#export, input.into()))
+            }
+            ProcMacroKind::Derive => quote::quote!(MACRO.proc_macro_derive(#export, input.into())),
+            ProcMacroKind::Attribute => {
+                quote::quote!(MACRO.proc_macro_attribute(#export, args.into(), input.into()))
+            }
+        }
+    });
+    let native_calls = fns.iter().map(|f| {
+        let ident = &f.name;
+        match f.kind {
+            ProcMacroKind::Attribute => quote::quote!(native::#ident(args, input)),
+            _ => quote::quote!(native::#ident(input)),
+        }
+    });
+    let dispatch = quote::quote! {
+        fn wasm(name: &str, args: TokenStream, input: TokenStream) -> TokenStream {
+            match name {
+                #(#names => #wasm_calls.into(),)*
+                _ => unreachable!(),
+            }
+        }
+
+        fn native(name: &str, args: TokenStream, input: TokenStream) -> TokenStream {
+            match name {
+                #(#names => #native_calls,)*
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    let invocations = cases.iter().map(|case| {
+        let corpus::Case {
+            name, args, input, ..
+        } = case;
+        quote::quote!([#name (#args) (#input)])
+    });
+    let cases = quote::quote! {
+        host_macro::run! { #(#invocations)* }
+    };
+    host::write(
+        host,
+        wasm,
+        compress,
+        Some(name),
+        &format!("{}\n{}", template, dispatch),
+        cases,
+    )
+}
+
+fn report(cases: &[corpus::Case], results: &[Value]) -> Result<(), anyhow::Error> {
+    let mut differences = 0;
+    for (case, result) in cases.iter().zip(results) {
+        if result["equal"].as_bool() == Some(true) {
+            continue;
+        }
+        differences += 1;
+
+        let minimized = &result["minimized"];
+        println!("{} ({}): the expansions differ", case.label, case.name);
+        println!("  input:     {}", case.input);
+        if !case.args.is_empty() {
+            println!("  args:      {}", case.args);
+        }
+        println!(
+            "  minimized: {}",
+            minimized["input"].as_str().unwrap_or_default()
+        );
+        println!("  wasm:      {}", outcome(&minimized["wasm"]));
+        println!("  native:    {}", outcome(&minimized["native"]));
+        println!();
+    }
+
+    println!(
+        "{} cases, {} equal, {} different",
+        cases.len(),
+        cases.len() - differences,
+        differences
+    );
+    if differences > 0 {
+        let error = anyhow::anyhow!("{} of {} expansions differ", differences, cases.len());
+        return Err(error.context(ErrorCode::DifferencesFound));
+    }
+    Ok(())
+}
+
+fn outcome(value: &Value) -> String {
+    match (value["tokens"].as_str(), value["panic"].as_str()) {
+        (Some(tokens), _) => tokens.to_string(),
+        (None, Some(message)) => format!("panicked: {}", message),
+        (None, None) => "-".to_string(),
+    }
+}
@@ -0,0 +1,91 @@
+// The reductions tried when minimizing an input. Like `host.rs`, this is part of the generated proc macro,
+// as the module `reduce`, and is only a module of cargo watt for its tests.
+
+use proc_macro2::{Group, TokenStream, TokenTree};
+use std::rc::Rc;
+
+/// `stream` with one group emptied or one token tree removed, the emptied groups first since they cut the most.
+/// The candidates are generated lazily, since nested groups have quadratically many and only a few are tried.
+pub fn reductions(stream: &TokenStream) -> Box<dyn Iterator<Item = TokenStream>> {
+    let trees: Rc<[TokenTree]> = stream.clone().into_iter().collect();
+
+    let emptied = {
+        let trees = trees.clone();
+        (0..trees.len()).filter_map(move |i| match &trees[i] {
+            TokenTree::Group(group) if !group.stream().is_empty() => Some(replace(
+                &trees,
+                i,
+                Some(with_stream(group, TokenStream::new())),
+            )),
+            _ => None,
+        })
+    };
+    let removed = (0..trees.len()).flat_map(move |i| {
+        let inner: Box<dyn Iterator<Item = TokenStream>> = match &trees[i] {
+            TokenTree::Group(group) => {
+                let (trees, group) = (trees.clone(), group.clone());
+                Box::new(
+                    reductions(&group.stream())
+                        .map(move |inner| replace(&trees, i, Some(with_stream(&group, inner)))),
+                )
+            }
+            _ => Box::new(std::iter::empty()),
+        };
+        let trees = trees.clone();
+        std::iter::once_with(move || replace(&trees, i, None)).chain(inner)
+    });
+    Box::new(emptied.chain(removed))
+}
+
+/// `trees` with the one at `i` replaced.
+fn replace(trees: &[TokenTree], i: usize, replacement: Option<TokenTree>) -> TokenStream {
+    trees[..i]
+        .iter()
+        .cloned()
+        .chain(replacement)
+        .chain(trees[i + 1..].iter().cloned())
+        .collect()
+}
+
+fn with_stream(group: &Group, stream: TokenStream) -> TokenTree {
+    let mut new = Group::new(group.delimiter(), stream);
+    new.set_span(group.span());
+    TokenTree::Group(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reductions_of(input: &str) -> Vec<String> {
+        reductions(&input.parse().unwrap())
+            .map(|stream| stream.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn emptied_groups_come_first() {
+        assert_eq!(
+            reductions_of("a (b c) d"),
+            ["a () d", "(b c) d", "a d", "a (c) d", "a (b) d", "a (b c)"]
+        );
+    }
+
+    #[test]
+    fn nested_groups() {
+        assert_eq!(
+            reductions_of("{ [x] }"),
+            ["{ }", "", "{ [] }", "{ }", "{ [] }"]
+        );
+    }
+
+    #[test]
+    fn nothing_to_reduce() {
+        assert!(reductions_of("").is_empty());
+    }
+
+    #[test]
+    fn one_removal_per_token() {
+        assert_eq!(reductions_of("a b c"), ["b c", "a c", "a b"]);
+    }
+}
@@ -0,0 +1,212 @@
+//! `cargo watt expand` runs a macro of a wasm file on some input and prints the result.
+//! Outside of a proc macro there is no `proc_macro` to hand to watt, so the macro runs inside a generated
+//! proc macro crate (see `host`), which writes the output to a file.
+
+use crate::{
+    host,
+    patch::bundle,
+    report::ErrorCode,
+    utils,
+    wasm::{self, MacroMetadata, ProcMacroFn, ProcMacroKind},
+};
+use anyhow::Context;
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Expands the macro `macro_name` of the wasm file or shim crate `path`.
+/// `input` is read from stdin if no file is given, `args` are the arguments of an attribute macro.
+pub fn expand(
+    path: &Path,
+    macro_name: Option<&str>,
+    input: Option<&Path>,
+    args: &str,
+) -> Result<(), anyhow::Error> {
+    let (wasm_file, fns) = if path.is_dir() {
+        shim_macros(path)?
+    } else {
+        let wasm = wasm::read_wasm_file(path)?;
+        let metadata = MacroMetadata::read(&wasm)?.with_context(|| {
+            format!(
+                "'{}' contains no '{}' section, expand the shim crate instead",
+                path.display(),
+                wasm::metadata::SECTION
+            )
+        })?;
+        (path.to_path_buf(), metadata.fns)
+    };
+    let f = select(&fns, macro_name)?;
+
+    let input = match input {
+        Some(file) => std::fs::read_to_string(file)
+            .with_context(|| format!("cannot read '{}'", file.display()))?,
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+
+    let input: proc_macro2::TokenStream = input
+        .parse()
+        .map_err(|e| anyhow::anyhow!("failed to tokenize the input: {:?}", e))?;
+    let args: proc_macro2::TokenStream = args
+        .parse()
+        .map_err(|e| anyhow::anyhow!("failed to tokenize the arguments: {:?}", e))?;
+    let kind = match f.kind {
+        ProcMacroKind::Macro | ProcMacroKind::ProcMacroHack => "macro",
+        ProcMacroKind::Derive => "derive",
+        ProcMacroKind::Attribute => "attribute",
+    };
+    let invocation = host::invocation(&f.export, kind, args, input);
+
+    let host = utils::Tempdir::new().context("failed to create temporary directory")?;
+    let compress = wasm_file.extension().is_some_and(|e| e == "deflate");
+    let wasm = std::fs::read(&wasm_file)?;
+    host::write(
+        &host,
+        &wasm,
+        compress,
+        None,
+        host::EXPAND,
+        quote::quote!(host_macro::expand! { #invocation }),
+    )?;
+    let results = host::run(&host, "expand", true, |_| {})
+        .with_context(|| format!("failed to expand '{}'", f.name))
+        .context(ErrorCode::ExpansionFailed)?;
+
+    let result = &results[0];
+    let tokens = match (result["tokens"].as_str(), result["panic"].as_str()) {
+        (Some(tokens), _) => tokens.to_string(),
+        (None, message) => {
+            let error = anyhow::anyhow!("'{}' panicked: {}", f.name, message.unwrap_or_default());
+            return Err(error.context(ErrorCode::ExpansionFailed));
+        }
+    };
+    println!("{}", rustfmt(&tokens).unwrap_or(tokens));
+    Ok(())
+}
+
+/// The wasm file of the shim crate in `directory` and the macros in its `lib.rs`.
+fn shim_macros(directory: &Path) -> Result<(PathBuf, Vec<ProcMacroFn>), anyhow::Error> {
+    let lib_path = directory.join("src/lib.rs");
+    let lib = std::fs::read_to_string(&lib_path)
+        .with_context(|| format!("cannot read '{}'", lib_path.display()))?;
+    let file = syn::parse_file(&lib)?;
+
+    let fns = file
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            syn::Item::Fn(item_fn) => shim_fn(item_fn),
+            _ => None,
+        })
+        .collect();
+
+    // the facades of a bundle call into the module of its runtime crate
+    let wasm_dir = match bundle::runtime_of(directory) {
+        Some(runtime) => runtime.join("src"),
+        None => directory.join("src"),
+    };
+    let wasm_file = std::fs::read_dir(&wasm_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| {
+            let name = path.to_string_lossy();
+            name.ends_with(".wasm") || name.ends_with(".wasm.deflate")
+        })
+        .with_context(|| format!("no wasm file in '{}'", wasm_dir.display()))?;
+
+    Ok((wasm_file, fns))
+}
+
+/// Recognizes the fns generated by `ProcMacroFn::to_tokens`, which consist of a call to `__watt_record`,
+/// or to `MACRO` in shims generated before recording was added.
+fn shim_fn(item_fn: &syn::ItemFn) -> Option<ProcMacroFn> {
+    let (kind, args) = match item_fn.block.stmts.last()? {
+        syn::Stmt::Expr(syn::Expr::Call(call)) => {
+            (string_literal(call.args.iter().nth(1)?)?, &call.args)
+        }
+        syn::Stmt::Expr(syn::Expr::MethodCall(call)) => (call.method.to_string(), &call.args),
+        _ => return None,
+    };
+    let kind = match kind.as_str() {
+        "macro" | "proc_macro" => ProcMacroKind::Macro,
+        "derive" | "proc_macro_derive" => ProcMacroKind::Derive,
+        "attribute" | "proc_macro_attribute" => ProcMacroKind::Attribute,
+        _ => return None,
+    };
+    let export = string_literal(args.first()?)?;
+    Some(ProcMacroFn {
+        name: item_fn.sig.ident.clone(),
+        export,
+        attrs: item_fn.attrs.clone(),
+        kind,
+    })
+}
+
+fn string_literal(expr: &syn::Expr) -> Option<String> {
+    match expr {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(string),
+            ..
+        }) => Some(string.value()),
+        _ => None,
+    }
+}
+
+fn select<'a>(
+    fns: &'a [ProcMacroFn],
+    name: Option<&str>,
+) -> Result<&'a ProcMacroFn, anyhow::Error> {
+    let names = || {
+        fns.iter()
+            .map(|f| f.name.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match name {
+        Some(name) => {
+            let found: Vec<&ProcMacroFn> = fns
+                .iter()
+                .filter(|f| f.name == name || f.export == name)
+                .collect();
+            match found[..] {
+                [f] => Ok(f),
+                [] => anyhow::bail!("no macro '{}', expected one of {}", name, names()),
+                // the crates of a bundle can have macros of the same name
+                _ => {
+                    let exports: Vec<&str> = found.iter().map(|f| f.export.as_str()).collect();
+                    anyhow::bail!(
+                        "'{}' is ambiguous, select one of {}",
+                        name,
+                        exports.join(", ")
+                    )
+                }
+            }
+        }
+        None if fns.len() == 1 => Ok(&fns[0]),
+        None if fns.is_empty() => anyhow::bail!("there are no macros to expand"),
+        None => anyhow::bail!("select a macro with --macro, one of {}", names()),
+    }
+}
+
+/// Formats `tokens` with rustfmt, which fails if they aren't items (e.g. the expression of a fn-like macro).
+fn rustfmt(tokens: &str) -> Option<String> {
+    let mut child = Command::new("rustfmt")
+        .args(["--emit", "stdout", "--edition", "2018"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(tokens.as_bytes()).ok()?;
+    let output = child.wait_with_output().ok()?;
+    if !output.status.success() {
+        log::debug!("rustfmt failed, printing the tokens unformatted");
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
@@ -0,0 +1,89 @@
+//! `cargo watt gc` removes the shims from the global store which no project points to anymore.
+
+use crate::{patch::store, status, utils::human_size};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+fn dir_size(dir: &Path) -> u64 {
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+pub fn gc(dry_run: bool) -> Result<(), anyhow::Error> {
+    let store = match store::shims_dir() {
+        Some(store) if store.exists() => store,
+        _ => {
+            log::info!("the global store is empty");
+            return Ok(());
+        }
+    };
+
+    // a project being patched registers itself and inserts its shims while holding the lock
+    let _lock = store::lock()?;
+    let mut projects = Vec::new();
+    let mut referenced = Vec::new();
+    for root in store::projects() {
+        // projects which have been deleted or moved don't need their shims anymore
+        if !root.join("Cargo.toml").exists() {
+            log::debug!("forgetting {}, it doesn't exist anymore", root.display());
+            continue;
+        }
+        let dirs = status::shim_dirs(&root).with_context(|| {
+            format!(
+                "failed to read the patches of {}, fix or delete it first",
+                root.display()
+            )
+        })?;
+        let in_store: Vec<PathBuf> = dirs
+            .into_iter()
+            .filter(|dir| store::contains(dir))
+            .collect();
+        if !in_store.is_empty() {
+            referenced.extend(in_store);
+            projects.push(root);
+        }
+    }
+
+    let mut unreferenced: Vec<PathBuf> = std::fs::read_dir(&store)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    unreferenced.retain(|dir| {
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.clone());
+        !referenced.contains(&dir)
+    });
+    unreferenced.sort();
+
+    let mut freed = 0;
+    for dir in &unreferenced {
+        freed += dir_size(dir);
+        let name = dir.file_name().unwrap().to_string_lossy();
+        if dry_run {
+            log::info!("would remove {}", name);
+        } else {
+            log::info!("removing {}", name);
+            std::fs::remove_dir_all(dir)
+                .with_context(|| format!("failed to delete '{}'", dir.display()))?;
+        }
+    }
+
+    if dry_run {
+        log::info!(
+            "{} shims are unused, removing them would free {}",
+            unreferenced.len(),
+            human_size(freed)
+        );
+    } else {
+        store::set_projects(&projects)?;
+        log::info!(
+            "removed {} shims, freed {}",
+            unreferenced.len(),
+            human_size(freed)
+        );
+    }
+    Ok(())
+}
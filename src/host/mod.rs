@@ -0,0 +1,127 @@
+//! watt only takes input from inside a proc macro, so `expand`, `replay`, `difftest` and `bench` run the wasm
+//! module in a generated workspace: the proc macro crate `host-macro` (starting with `prelude.rs`) and the crate
+//! `host-cases`, whose build invokes it. The macro writes its results as JSON to `$CARGO_WATT_HOST_RESULTS`.
+
+use crate::{build, patch::store, report::ErrorCode};
+use anyhow::Context;
+use serde_json::Value;
+use std::{path::Path, process::Command};
+
+const PRELUDE: &str = include_str!("prelude.rs");
+
+/// The code of the macro `expand!`, which runs the macros exported by the wasm module on
+/// the [`invocation`]s passed to it and writes their outputs, or the panic messages.
+pub const EXPAND: &str = r#"
+#[proc_macro]
+pub fn expand(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let results = invocations(tokens)
+        .iter()
+        .map(|parts| {
+            let export = parts[0].to_string();
+            let kind = parts[1].to_string();
+            let args = group_stream(parts.get(2));
+            let input = group_stream(parts.get(3));
+            let outcome = catch(|| match kind.as_str() {
+                "attribute" => MACRO.proc_macro_attribute(&export, args.into(), input.into()).into(),
+                "derive" => MACRO.proc_macro_derive(&export, input.into()).into(),
+                _ => MACRO.proc_macro(&export, input.into()).into(),
+            });
+            outcome_json(&outcome)
+        })
+        .collect();
+    write_results(serde_json::Value::Array(results))
+}
+"#;
+
+/// An invocation `[export kind (args) (input)]` of the macro in [`EXPAND`],
+/// `kind` is either `macro`, `derive` or `attribute`, as in the recordings of the shims.
+pub fn invocation(
+    export: &str,
+    kind: &str,
+    args: proc_macro2::TokenStream,
+    input: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    let export = quote::format_ident!("{}", export);
+    let kind = quote::format_ident!("{}", kind);
+    quote::quote!([#export #kind (#args) (#input)])
+}
+
+/// Writes the host workspace into `host`. `host-macro` includes the `wasm` module and the `code`,
+/// `host-cases` consists of the `cases`, usually an invocation of a macro in `code`.
+/// With `native`, the package in `<host>/native` is a dependency of `host-macro` as `native`.
+pub fn write(
+    host: &Path,
+    wasm: &[u8],
+    compress: bool,
+    native: Option<&str>,
+    code: &str,
+    cases: proc_macro2::TokenStream,
+) -> Result<(), anyhow::Error> {
+    let file_name = build::wasm_file_name("host", compress);
+
+    let mut macro_manifest = String::from(
+        "[package]\nname = \"host-macro\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n[lib]\nproc-macro = true\n\n[dependencies]\nwatt = \"0.4\"\nproc-macro2 = \"1.0\"\nserde_json = \"1.0\"\n",
+    );
+    if compress {
+        macro_manifest.push_str("miniz_oxide = \"0.3\"\nonce_cell = \"1.4\"\n");
+    }
+    let mut members = vec!["\"macro\"", "\"cases\""];
+    if let Some(native) = native {
+        macro_manifest.push_str(&format!(
+            "native = {{ package = \"{}\", path = \"../native\" }}\n",
+            native
+        ));
+        members.push("\"native\"");
+    }
+
+    let statics = build::wasm_statics(&file_name, compress, false);
+    let macro_lib = format!("{}\n{}\n{}", PRELUDE, statics, code);
+    let cases_manifest = "[package]\nname = \"host-cases\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n[dependencies]\nhost-macro = { path = \"../macro\" }\n";
+
+    std::fs::write(
+        host.join("Cargo.toml"),
+        format!("[workspace]\nmembers = [{}]\n", members.join(", ")),
+    )?;
+    std::fs::create_dir_all(host.join("macro/src"))?;
+    std::fs::write(host.join("macro/Cargo.toml"), macro_manifest)?;
+    std::fs::write(host.join("macro/src").join(&file_name), wasm)?;
+    std::fs::write(host.join("macro/src/lib.rs"), macro_lib)?;
+    std::fs::create_dir_all(host.join("cases/src"))?;
+    std::fs::write(host.join("cases/Cargo.toml"), cases_manifest)?;
+    std::fs::write(host.join("cases/src/lib.rs"), cases.to_string())?;
+    Ok(())
+}
+
+/// Builds the host written by [`write`], which runs the macros, and returns what they wrote to `$CARGO_WATT_HOST_RESULTS`.
+/// The target directory is cached as `cache_name`, `configure` can set up the build.
+pub fn run(
+    host: &Path,
+    cache_name: &str,
+    release: bool,
+    configure: impl FnOnce(&mut Command),
+) -> Result<Value, anyhow::Error> {
+    let results_file = host.join("results.json");
+    // watt (and the native crate) take a while to compile, so the target directory is kept around
+    let target_dir = store::cache_dir()
+        .map(|dir| dir.join(cache_name))
+        .unwrap_or_else(|| host.join("target").join(cache_name));
+    let mut command = Command::new("cargo");
+    command
+        .args(["build", "--workspace", "--all-features"])
+        .args(release.then_some("--release"))
+        .env("CARGO_TARGET_DIR", target_dir)
+        .env("CARGO_WATT_HOST_RESULTS", &results_file)
+        .current_dir(host);
+    configure(&mut command);
+    let output = command.output().context("failed to run cargo")?;
+    if !output.status.success() {
+        let error = anyhow::anyhow!(
+            "failed to build the macro host:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(error.context(ErrorCode::CompileFailed));
+    }
+
+    let results = std::fs::read(&results_file).context("the macro host wrote no results")?;
+    Ok(serde_json::from_slice(&results)?)
+}
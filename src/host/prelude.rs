@@ -0,0 +1,60 @@
+// The start of every proc macro crate generated by `host::write`. This is not a module of cargo watt,
+// the `WASM` and `MACRO` statics and the code of the command are appended to it.
+#![allow(dead_code)]
+
+extern crate proc_macro;
+
+use proc_macro2::{TokenStream, TokenTree};
+use std::panic::{self, AssertUnwindSafe};
+
+/// The output of a macro, or the message it panicked with.
+type Outcome = Result<String, String>;
+
+/// The parts of the invocations `[part*]*` which `host-cases` passes to the macro.
+fn invocations(tokens: proc_macro::TokenStream) -> Vec<Vec<TokenTree>> {
+    TokenStream::from(tokens)
+        .into_iter()
+        .filter_map(|invocation| match invocation {
+            TokenTree::Group(group) => Some(group.stream().into_iter().collect()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The content of a part `(..)` of an invocation.
+fn group_stream(tree: Option<&TokenTree>) -> TokenStream {
+    match tree {
+        Some(TokenTree::Group(group)) => group.stream(),
+        _ => TokenStream::new(),
+    }
+}
+
+/// Runs `f` without printing its panic.
+fn catch(f: impl FnOnce() -> TokenStream) -> Outcome {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    panic::set_hook(hook);
+
+    result.map(|tokens| tokens.to_string()).map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panicked".to_string())
+    })
+}
+
+fn outcome_json(outcome: &Outcome) -> serde_json::Value {
+    match outcome {
+        Ok(tokens) => serde_json::json!({ "tokens": tokens }),
+        Err(message) => serde_json::json!({ "panic": message }),
+    }
+}
+
+/// Writes the `results` to `$CARGO_WATT_HOST_RESULTS`, where `host::run` reads them.
+fn write_results(results: serde_json::Value) -> proc_macro::TokenStream {
+    let path = std::env::var("CARGO_WATT_HOST_RESULTS").unwrap();
+    std::fs::write(path, results.to_string()).unwrap();
+    proc_macro::TokenStream::new()
+}
@@ -2,12 +2,24 @@ mod utils;
 mod utils_toml;
 mod wasm;
 
+mod bench;
 mod build;
+mod difftest;
+mod expand;
+mod gc;
+mod host;
 mod patch;
+mod profile;
+mod replay;
+mod report;
+mod status;
+mod unpatch;
 mod verify;
+mod watch;
 
 use anyhow::Context;
 use clap::Clap;
+use report::{ErrorCode, Event, MessageFormat};
 use std::path::PathBuf;
 
 #[derive(Clap, Debug)]
@@ -22,15 +34,47 @@ pub struct Input {
     #[cfg_attr(not(feature = "crates"), clap(hidden = true))]
     #[clap(long = "crate", conflicts_with = "path", conflicts_with = "git")]
     crate_: Option<String>,
+
+    #[cfg_attr(not(feature = "crates"), clap(hidden = true))]
+    #[clap(
+        long,
+        requires = "crate",
+        about = "version of the crate, defaults to the latest one"
+    )]
+    crate_version: Option<String>,
+
+    /// the checksum of `crate_version` in `Cargo.lock`, which lets the registry cache be used
+    #[clap(skip)]
+    crate_checksum: Option<String>,
 }
 impl Input {
-    pub fn crate_(crate_: String) -> Self {
+    pub fn path(path: PathBuf) -> Self {
+        Self {
+            path,
+            git: None,
+            crate_: None,
+            crate_version: None,
+            crate_checksum: None,
+        }
+    }
+
+    pub fn crate_(crate_: String, version: Option<String>) -> Self {
         Self {
             crate_: Some(crate_),
+            crate_version: version,
+            crate_checksum: None,
             path: PathBuf::default(),
             git: None,
         }
     }
+
+    /// The locked `version` of `crate_`, with its `checksum` from `Cargo.lock`.
+    pub fn locked_crate(crate_: String, version: String, checksum: Option<String>) -> Self {
+        Self {
+            crate_checksum: checksum,
+            ..Self::crate_(crate_, Some(version))
+        }
+    }
 }
 
 #[derive(Clap, Debug)]
@@ -43,8 +87,36 @@ pub struct CompilationOptions {
 
     #[clap(long)]
     compress: bool,
+
+    #[clap(long, about = "allow an additional wasm import (module::name)")]
+    allow_import: Vec<String>,
+
+    #[clap(long, about = "print where the code size comes from (table or json)")]
+    size_report: Option<wasm::size::ReportFormat>,
 }
 impl CompilationOptions {
+    /// The options which affect the generated wasm file, to tell whether a shim needs to be rebuilt.
+    fn fingerprint(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if self.no_wasm_strip {
+            options.push("--no-wasm-strip".to_string());
+        }
+        if self.no_wasm_opt {
+            options.push("--no-wasm-opt".to_string());
+        }
+        if self.compress {
+            options.push("--compress".to_string());
+        }
+        let mut allow_import = self.allow_import.clone();
+        allow_import.sort();
+        options.extend(
+            allow_import
+                .iter()
+                .map(|import| format!("--allow-import={}", import)),
+        );
+        options
+    }
+
     fn verify(&self) -> Result<(), anyhow::Error> {
         let exists = |cmd: &str| {
             std::process::Command::new(cmd)
@@ -63,6 +135,82 @@ impl CompilationOptions {
     }
 }
 
+#[derive(Clap, Debug)]
+pub struct PackageSelection {
+    #[clap(
+        long,
+        short = 'p',
+        about = "only consider the dependencies of this workspace member"
+    )]
+    package: Vec<String>,
+
+    #[clap(long, about = "ignore the dependencies of this workspace member")]
+    exclude: Vec<String>,
+
+    #[clap(
+        long,
+        about = "skip proc macros which are only used as dev-dependencies"
+    )]
+    no_dev: bool,
+}
+
+#[derive(Clap, Debug)]
+pub struct PatchOptions {
+    #[clap(
+        long,
+        about = "build every crate even if some fail and only patch the successful ones"
+    )]
+    keep_going: bool,
+
+    #[clap(long, about = "rebuild all crates, even if they are up to date")]
+    force: bool,
+
+    #[clap(
+        long,
+        short = 'j',
+        about = "number of crates to build at once, defaults to a quarter of the cores"
+    )]
+    jobs: Option<usize>,
+
+    #[clap(long, about = "fail if cargo doesn't use one of the patches")]
+    strict: bool,
+
+    #[clap(
+        long,
+        about = "try the patches on a copy of the project first and leave out the ones which break `cargo check`"
+    )]
+    trial: bool,
+
+    #[clap(
+        long,
+        about = "arguments for `cargo check` in --trial",
+        default_value = "--workspace --all-targets",
+        allow_hyphen_values = true
+    )]
+    check_args: String,
+
+    #[clap(
+        long,
+        about = "put the shims into a store shared by all projects instead of .watt-patched"
+    )]
+    global_store: bool,
+
+    /// set by `cargo watt bundle`
+    #[clap(skip)]
+    bundle: bool,
+}
+impl PatchOptions {
+    /// The arguments for `cargo check`, if `--trial` is enabled.
+    fn trial(&self) -> Option<Vec<String>> {
+        self.trial.then(|| {
+            self.check_args
+                .split_whitespace()
+                .map(String::from)
+                .collect()
+        })
+    }
+}
+
 #[derive(Debug, Clap)]
 #[clap(setting = clap::AppSettings::ColoredHelp, bin_name = "cargo watt", about = clap::crate_description!())]
 pub enum Options {
@@ -83,6 +231,17 @@ pub enum Options {
 
         #[clap(long, about = "don't delete the temporary build directory")]
         keep_tmp: bool,
+
+        #[clap(
+            long,
+            default_value = "crate",
+            possible_values = &["crate", "wasm"],
+            about = "only produce the wasm file (at -o) instead of the whole crate"
+        )]
+        emit: build::Emit,
+
+        #[clap(long, default_value = "human", possible_values = &["human", "json"])]
+        message_format: MessageFormat,
     },
     Verify {
         #[clap(required = true)]
@@ -93,6 +252,59 @@ pub enum Options {
 
         #[clap(flatten)]
         compilation_options: CompilationOptions,
+
+        #[clap(long, default_value = "human", possible_values = &["human", "json"])]
+        message_format: MessageFormat,
+    },
+    #[clap(
+        about = "compare the wasm and native expansions of a macro crate, in a generated crate built by cargo"
+    )]
+    Difftest {
+        #[clap(flatten)]
+        input: Input,
+
+        #[clap(flatten)]
+        compilation_options: CompilationOptions,
+
+        #[clap(long, about = "test this wasm file instead of compiling the crate")]
+        wasm: Option<PathBuf>,
+
+        #[clap(
+            long,
+            about = "file or directory with additional inputs, can be passed multiple times"
+        )]
+        corpus: Vec<PathBuf>,
+
+        #[clap(long, about = "don't delete the temporary build directory")]
+        keep_tmp: bool,
+    },
+    #[clap(
+        about = "time builds and expansions of a macro crate natively and as a shim, in generated crates built by cargo"
+    )]
+    Bench {
+        #[clap(flatten)]
+        input: Input,
+
+        #[clap(flatten)]
+        compilation_options: CompilationOptions,
+
+        #[clap(
+            long,
+            about = "benchmark this wasm file instead of compiling the crate"
+        )]
+        wasm: Option<PathBuf>,
+
+        #[clap(
+            long,
+            about = "crate whose invocations of the macros are timed, defaults to the tests and examples of the crate"
+        )]
+        consumer: Option<PathBuf>,
+
+        #[clap(
+            long,
+            about = "build a project patched by `cargo watt patch` with and without the patches instead"
+        )]
+        project: Option<PathBuf>,
     },
     Patch {
         #[clap(default_value = ".")]
@@ -100,6 +312,152 @@ pub enum Options {
 
         #[clap(flatten)]
         compilation_options: CompilationOptions,
+
+        #[clap(flatten)]
+        selection: PackageSelection,
+
+        #[clap(flatten)]
+        options: PatchOptions,
+
+        #[clap(long, about = "undo the last run of `cargo watt patch`")]
+        rollback: bool,
+
+        #[clap(long, default_value = "human", possible_values = &["human", "json"])]
+        message_format: MessageFormat,
+    },
+    Bundle {
+        #[clap(default_value = ".")]
+        path: PathBuf,
+
+        #[clap(flatten)]
+        compilation_options: CompilationOptions,
+
+        #[clap(flatten)]
+        selection: PackageSelection,
+
+        #[clap(flatten)]
+        options: PatchOptions,
+
+        #[clap(long, default_value = "human", possible_values = &["human", "json"])]
+        message_format: MessageFormat,
+    },
+    Shim {
+        #[clap(required = true, about = "a wasm file produced by `cargo watt build`")]
+        file: PathBuf,
+
+        #[clap(short = 'o')]
+        out_dir: Option<PathBuf>,
+
+        #[clap(long)]
+        overwrite: bool,
+    },
+    #[clap(
+        about = "run a macro on some input, in a generated crate built by cargo (which needs watt, proc-macro2 and serde_json)"
+    )]
+    Expand {
+        #[clap(required = true, about = "a wasm file or a shim crate")]
+        file: PathBuf,
+
+        #[clap(
+            long = "macro",
+            about = "the macro to run, can be omitted if there is only one"
+        )]
+        macro_: Option<String>,
+
+        #[clap(long, about = "file with the input of the macro, defaults to stdin")]
+        input: Option<PathBuf>,
+
+        #[clap(long, about = "arguments of an attribute macro")]
+        args: Option<String>,
+    },
+    #[clap(
+        about = "run recorded invocations against a wasm file, in a generated crate built by cargo"
+    )]
+    Replay {
+        #[clap(
+            about = "the recordings, defaults to $CARGO_WATT_RECORD or target/cargo-watt/recordings"
+        )]
+        dir: Option<PathBuf>,
+
+        #[clap(
+            long,
+            required = true,
+            about = "the wasm file to replay the recordings against"
+        )]
+        wasm: PathBuf,
+    },
+    Watch {
+        #[clap(default_value = ".")]
+        path: PathBuf,
+
+        #[clap(flatten)]
+        compilation_options: CompilationOptions,
+
+        #[clap(
+            short = 'o',
+            about = "where to write the wasm file, defaults to target/cargo-watt/<crate>.wasm in the crate"
+        )]
+        out_file: Option<PathBuf>,
+
+        #[clap(
+            long,
+            about = "file to touch after every rebuild so that cargo expands its crate again, can be passed multiple times"
+        )]
+        touch: Vec<PathBuf>,
+    },
+    ProfileReport {
+        #[clap(
+            about = "the log written by shims with CARGO_WATT_PROFILE set, defaults to $CARGO_WATT_PROFILE or target/cargo-watt/profile.log"
+        )]
+        file: Option<PathBuf>,
+
+        #[clap(long, default_value = "table", possible_values = &["table", "json"])]
+        format: wasm::size::ReportFormat,
+    },
+    Unpatch {
+        #[clap(default_value = ".")]
+        path: PathBuf,
+
+        #[clap(
+            long = "crate",
+            about = "only remove the patch for this crate, can be passed multiple times"
+        )]
+        crates: Vec<String>,
+    },
+    Status {
+        #[clap(default_value = ".")]
+        path: PathBuf,
+
+        #[clap(flatten)]
+        selection: PackageSelection,
+
+        #[clap(long, about = "show which workspace members pull in which proc macros")]
+        tree: bool,
+    },
+    AuditImports {
+        #[clap(required = true)]
+        file: PathBuf,
+
+        #[clap(long, about = "allow an additional wasm import (module::name)")]
+        allow_import: Vec<String>,
+    },
+    Size {
+        #[clap(required = true)]
+        file: PathBuf,
+
+        #[clap(long, default_value = "table", possible_values = &["table", "json"])]
+        format: wasm::size::ReportFormat,
+
+        #[clap(
+            long,
+            default_value = "20",
+            about = "number of crates and functions to show"
+        )]
+        top: usize,
+    },
+    Gc {
+        #[clap(long, about = "only list the shims which would be removed")]
+        dry_run: bool,
     },
 }
 impl Options {
@@ -107,28 +465,71 @@ impl Options {
         match self {
             Options::Build { input, .. } => input,
             Options::Verify { input, .. } => input,
-            Options::Patch { .. } => panic!("no input in patch subcommand"),
+            Options::Difftest { input, .. } => input,
+            Options::Bench { input, .. } => input,
+            _ => panic!("no input in {:?}", self),
         }
     }
-    fn compilation_options(&self) -> &CompilationOptions {
+    fn compilation_options(&self) -> Option<&CompilationOptions> {
         match self {
             Options::Build {
                 compilation_options,
                 ..
-            } => compilation_options,
+            } => Some(compilation_options),
             Options::Verify {
                 compilation_options,
                 ..
-            } => compilation_options,
+            } => Some(compilation_options),
+            Options::Difftest { wasm: Some(_), .. } => None,
+            Options::Difftest {
+                compilation_options,
+                ..
+            } => Some(compilation_options),
+            Options::Bench { wasm: Some(_), .. }
+            | Options::Bench {
+                project: Some(_), ..
+            } => None,
+            Options::Bench {
+                compilation_options,
+                ..
+            } => Some(compilation_options),
+            Options::Patch { rollback: true, .. } => None,
             Options::Patch {
                 compilation_options,
                 ..
-            } => compilation_options,
+            } => Some(compilation_options),
+            Options::Bundle {
+                compilation_options,
+                ..
+            } => Some(compilation_options),
+            Options::Watch {
+                compilation_options,
+                ..
+            } => Some(compilation_options),
+            Options::Shim { .. }
+            | Options::Expand { .. }
+            | Options::Replay { .. }
+            | Options::ProfileReport { .. }
+            | Options::Unpatch { .. }
+            | Options::Status { .. }
+            | Options::AuditImports { .. }
+            | Options::Size { .. }
+            | Options::Gc { .. } => None,
+        }
+    }
+    fn message_format(&self) -> MessageFormat {
+        match self {
+            Options::Build { message_format, .. } => *message_format,
+            Options::Verify { message_format, .. } => *message_format,
+            Options::Patch { message_format, .. } => *message_format,
+            Options::Bundle { message_format, .. } => *message_format,
+            _ => MessageFormat::Human,
         }
     }
     fn keep_tmp(&self) -> bool {
         match self {
             Options::Build { keep_tmp, .. } => *keep_tmp,
+            Options::Difftest { keep_tmp, .. } => *keep_tmp,
             _ => false,
         }
     }
@@ -142,6 +543,7 @@ fn main() {
     let args = std::env::args().filter(|arg| arg != "watt");
     let options = Options::parse_from(args);
     if let Err(e) = run(options) {
+        report::emit(Event::Error { error: &e });
         log::error!("{:?}", e);
         std::process::exit(1);
     }
@@ -153,36 +555,137 @@ impl Input {
 
         if let Some(git) = &self.git {
             log::info!("git clone '{}' into temporary directory...", &git);
-            utils::clone_git_into(&directory, git)?;
+            utils::clone_git_into(&directory, git).context(ErrorCode::FetchFailed)?;
         } else if let Some(crate_) = &self.crate_ {
-            log::info!("download crate '{}' into temporary directory...", crate_);
+            match &self.crate_version {
+                Some(version) => log::info!(
+                    "download crate '{} {}' into temporary directory...",
+                    crate_,
+                    version
+                ),
+                None => log::info!("download crate '{}' into temporary directory...", crate_),
+            }
             #[cfg(feature = "crates")]
-            utils::download_crate(&directory, crate_)
-                .context("failed to download and extract crate")?;
+            utils::download_crate(
+                &directory,
+                crate_,
+                self.crate_version.as_deref(),
+                self.crate_checksum.as_deref(),
+            )
+            .context(ErrorCode::FetchFailed)?;
             #[cfg(not(feature = "crates"))]
             panic!("the crate was compiled without the 'crates' feature flag");
         } else {
             let cargo_toml = self.path.join("Cargo.toml");
-            anyhow::ensure!(
-            cargo_toml.exists(),
-            "No Cargo.toml found. Use the --git or --crate flag if you want to use a remote crate."
-        );
-            utils::copy_all(&self.path, &directory).context("failed to copy to tmp dir")?;
+            if !cargo_toml.exists() {
+                let error = anyhow::anyhow!("No Cargo.toml found. Use the --git or --crate flag if you want to use a remote crate.");
+                return Err(error.context(ErrorCode::InvalidManifest));
+            }
+            utils::copy_all(&self.path, &directory)
+                .context("failed to copy to tmp dir")
+                .context(ErrorCode::FetchFailed)?;
         }
 
+        report::emit(Event::SourceFetched {
+            source: &self.source(),
+            directory: &directory,
+        });
+
         Ok(directory)
     }
+
+    fn source(&self) -> String {
+        match (&self.git, &self.crate_) {
+            (Some(git), _) => format!("git+{}", git),
+            (None, Some(crate_)) => match &self.crate_version {
+                Some(version) => format!("crates-io+{}@{}", crate_, version),
+                None => format!("crates-io+{}", crate_),
+            },
+            (None, None) => format!("path+{}", self.path.display()),
+        }
+    }
 }
 
 fn run(options: Options) -> Result<(), anyhow::Error> {
-    options.compilation_options().verify()?;
+    report::set_message_format(options.message_format());
+    if let Some(compilation_options) = options.compilation_options() {
+        compilation_options.verify()?;
+    }
 
-    if let Options::Patch {
-        path,
-        compilation_options,
-    } = options
-    {
-        return patch::patch(&path, &compilation_options);
+    match options {
+        Options::Patch {
+            path,
+            compilation_options,
+            selection,
+            options,
+            rollback,
+            ..
+        } => {
+            if rollback {
+                return patch::rollback(&path);
+            }
+            return patch::patch(&path, &compilation_options, &selection, &options);
+        }
+        Options::Bundle {
+            path,
+            compilation_options,
+            selection,
+            mut options,
+            ..
+        } => {
+            options.bundle = true;
+            return patch::patch(&path, &compilation_options, &selection, &options);
+        }
+        Options::Shim {
+            file,
+            out_dir,
+            overwrite,
+        } => return build::shim(&file, out_dir, overwrite),
+        Options::Expand {
+            file,
+            macro_,
+            input,
+            args,
+        } => {
+            return expand::expand(
+                &file,
+                macro_.as_deref(),
+                input.as_deref(),
+                args.as_deref().unwrap_or_default(),
+            )
+        }
+        Options::Replay { dir, wasm } => {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => build::shim_output("CARGO_WATT_RECORD", "recordings")?,
+            };
+            return replay::replay(&dir, &wasm);
+        }
+        Options::Watch {
+            path,
+            compilation_options,
+            out_file,
+            touch,
+        } => return watch::watch(&path, out_file, &compilation_options, &touch),
+        Options::ProfileReport { file, format } => {
+            return profile::profile_report(file.as_deref(), format)
+        }
+        Options::Bench {
+            project: Some(project),
+            ..
+        } => return bench::project(&project),
+        Options::Unpatch { path, crates } => return unpatch::unpatch(&path, &crates),
+        Options::Status {
+            path,
+            selection,
+            tree,
+        } => return status::status(&path, &selection, tree),
+        Options::AuditImports { file, allow_import } => {
+            return wasm::audit::audit_imports(&file, &allow_import)
+        }
+        Options::Size { file, format, top } => return wasm::size::size(&file, format, top),
+        Options::Gc { dry_run } => return gc::gc(dry_run),
+        _ => {}
     }
 
     // copy crate (local directory, crates.io, git) into /tmp/cargo-watt-crate
@@ -195,6 +698,13 @@ fn run(options: Options) -> Result<(), anyhow::Error> {
     }
 
     match options {
+        Options::Build {
+            emit: build::Emit::Wasm,
+            overwrite,
+            compilation_options,
+            out_dir,
+            ..
+        } => build::emit_wasm(&tempdir, out_dir, &compilation_options, overwrite),
         Options::Build {
             only_copy_essential,
             overwrite,
@@ -207,13 +717,32 @@ fn run(options: Options) -> Result<(), anyhow::Error> {
             &compilation_options,
             only_copy_essential,
             overwrite,
+            None,
             false,
-        ),
+        )
+        .map(drop),
         Options::Verify {
             file,
             compilation_options,
             ..
         } => verify::verify(&tempdir, &compilation_options, &file),
-        Options::Patch { .. } => unreachable!(),
+        Options::Difftest {
+            compilation_options,
+            wasm,
+            corpus,
+            ..
+        } => difftest::difftest(&tempdir, &compilation_options, wasm.as_deref(), &corpus),
+        Options::Bench {
+            compilation_options,
+            wasm,
+            consumer,
+            ..
+        } => bench::bench(
+            &tempdir,
+            &compilation_options,
+            wasm.as_deref(),
+            consumer.as_deref(),
+        ),
+        _ => unreachable!(),
     }
 }
@@ -0,0 +1,280 @@
+//! `cargo watt bundle` compiles all proc macros into a single wasm module, so syn and proc-macro2 are included once.
+//! The module goes into a runtime crate `.watt-patched/watt-bundle-<hash>`, and the shims of the macros
+//! are thin facades which call into it under namespaced export names.
+
+use super::{compatible_version, shim_dir_name, ShimInfo};
+use crate::{
+    build,
+    report::ErrorCode,
+    utils, utils_toml,
+    wasm::{self, modifications, MacroMetadata, TargetDir},
+    CompilationOptions,
+};
+use anyhow::Context;
+use cargo_metadata::Package;
+use std::path::{Path, PathBuf};
+
+pub const RUNTIME_CRATE: &str = "watt-bundle";
+
+/// A proc macro which goes into the bundle.
+pub struct Member<'a> {
+    pub package: &'a Package,
+    pub shim: ShimInfo,
+    pub checkout: Option<&'a Path>,
+}
+
+/// Distinguishes the crates, and versions of the same crate, in the export names of the bundle.
+fn namespace(package: &Package) -> String {
+    format!(
+        "{}_{}",
+        package.name.replace('-', "_"),
+        compatible_version(&package.version).replace('-', "_")
+    )
+}
+
+/// Builds the bundle of `members` and puts the runtime crate and the shims into `out_dir`.
+/// Returns the size of the bundled wasm file.
+pub fn build(
+    members: &[&Member],
+    out_dir: &Path,
+    compilation_options: &CompilationOptions,
+    target_dir: &TargetDir,
+) -> Result<usize, anyhow::Error> {
+    // the shims of a bundle only work together, so the runtime crate is specific to them
+    let mut hasher = utils::Fnv::new();
+    for member in members {
+        hasher.write(shim_dir_name(member.package).as_bytes());
+        hasher.write(member.shim.source.to_string().as_bytes());
+        hasher.write(
+            member
+                .shim
+                .checksum
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+    }
+    hasher.write(compilation_options.fingerprint().join(" ").as_bytes());
+    let runtime_dir = out_dir.join(format!("{}-{}", RUNTIME_CRATE, hasher.finish()));
+
+    let directory = utils::Tempdir::new().context("failed to create temporary directory")?;
+    let mut manifests = Vec::new();
+    let mut fns = Vec::new();
+    for member in members {
+        let package = member.package;
+        let fetched = member.shim.source.fetch(
+            &package.name,
+            &package.version.to_string(),
+            member.shim.checksum.as_deref(),
+            member.checkout,
+        )?;
+        let crate_dir = directory.join("crates").join(shim_dir_name(package));
+        std::fs::create_dir_all(&crate_dir)?;
+        utils::copy_all(&fetched, &crate_dir)?;
+
+        let manifest = utils::parse_validate_toml(&crate_dir.join("Cargo.toml"))?;
+        let namespace = namespace(package);
+        let member_fns = modifications::make_modifications(&crate_dir, &namespace)
+            .with_context(|| format!("failed to modify '{}'", package.name))
+            .context(ErrorCode::ModificationFailed)?;
+        // the members are linked into the bundle, which is the only crate compiled to a wasm module
+        modifications::make_rlib(&crate_dir.join("Cargo.toml"))?;
+
+        manifests.push(manifest);
+        fns.push(member_fns);
+    }
+
+    write_bundle_crate(&directory, members)?;
+    // the macros of all members, which `cargo watt expand` tells apart by their exports
+    let metadata = MacroMetadata {
+        crate_: RUNTIME_CRATE.to_string(),
+        version: "0.0.0".to_string(),
+        fns: fns.iter().flatten().cloned().collect(),
+    };
+    let wasm = wasm::build(
+        &directory,
+        RUNTIME_CRATE,
+        compilation_options,
+        Some(target_dir),
+        Some(&metadata),
+        false,
+    )?;
+
+    write_runtime_crate(
+        &runtime_dir,
+        &directory,
+        &wasm,
+        compilation_options.compress,
+    )?;
+    let runtime_name = runtime_dir.file_name().unwrap().to_string_lossy();
+    for ((member, manifest), fns) in members.iter().zip(manifests).zip(&fns) {
+        let shim_dir = out_dir.join(shim_dir_name(member.package));
+        write_facade_crate(&shim_dir, manifest, fns, &runtime_name)?;
+        member
+            .shim
+            .write(&shim_dir)
+            .context("failed to record the source of the shim")?;
+    }
+
+    Ok(wasm.len())
+}
+
+fn write_bundle_crate(directory: &Path, members: &[&Member]) -> Result<(), anyhow::Error> {
+    let base = format!(
+        "[package]\nname = \"{}\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n[lib]\nproc-macro = true\n\n[dependencies]\n",
+        RUNTIME_CRATE
+    );
+    let mut manifest: toml_edit::Document = modifications::cargo_toml(&base)?.parse()?;
+
+    let mut externs = Vec::new();
+    for member in members {
+        let package = member.package;
+        let key = namespace(package);
+        let crate_dir = Path::new("crates").join(shim_dir_name(package));
+
+        let mut dependency = toml_edit::InlineTable::default();
+        dependency.get_or_insert("package", package.name.as_str());
+        dependency.get_or_insert("path", crate_dir.to_string_lossy().as_ref());
+        // the crates are compiled with all features, like they would be on their own
+        let features: toml_edit::Value = package.features.keys().map(String::as_str).collect();
+        dependency.get_or_insert("features", features);
+        dependency.fmt();
+        manifest["dependencies"][&key] = toml_edit::value(dependency);
+
+        let ident = quote::format_ident!("{}", key);
+        externs.push(quote::quote!(extern crate #ident;));
+    }
+
+    std::fs::create_dir_all(directory.join("src"))?;
+    std::fs::write(
+        directory.join("Cargo.toml"),
+        manifest.to_string_in_original_order(),
+    )?;
+    std::fs::write(
+        directory.join("src/lib.rs"),
+        quote::quote!(#(#externs)*).to_string(),
+    )?;
+    Ok(())
+}
+
+/// The crate holding the bundled wasm module, which the facades depend on.
+fn write_runtime_crate(
+    runtime_dir: &Path,
+    directory: &Path,
+    wasm: &[u8],
+    compress: bool,
+) -> Result<(), anyhow::Error> {
+    let mut manifest: toml_edit::Document = format!(
+        "[package]\nname = \"{}\"\nversion = \"0.0.0\"\nedition = \"2018\"\npublish = false\n\n[dependencies]\n",
+        RUNTIME_CRATE
+    )
+    .parse()?;
+    manifest["dependencies"]["watt"] = toml_edit::value("0.4");
+    if compress {
+        manifest["dependencies"]["miniz_oxide"] = toml_edit::value("0.3");
+        manifest["dependencies"]["once_cell"] = toml_edit::value("1.4");
+    }
+
+    let file_name = build::wasm_file_name(&RUNTIME_CRATE.replace('-', "_"), compress);
+    let statics = build::wasm_statics(&file_name, compress, true);
+    // for the `__watt_macro` of the facades
+    let lib = quote::quote! {
+        pub use watt;
+        #statics
+    };
+
+    let src = runtime_dir.join("src");
+    std::fs::create_dir_all(&src)?;
+    std::fs::write(
+        runtime_dir.join("Cargo.toml"),
+        manifest.to_string_in_original_order(),
+    )?;
+    std::fs::write(src.join(&file_name), wasm)?;
+    std::fs::write(src.join("lib.rs"), lib.to_string())?;
+    std::fs::rename(
+        directory.join("Cargo.lock"),
+        runtime_dir.join("Cargo.watt.lock"),
+    )?;
+    if let Err(e) = utils::cargo_fmt(runtime_dir) {
+        log::warn!("failed to format crate: {}", e);
+    }
+    Ok(())
+}
+
+/// The shim of a bundled proc macro, which only forwards to the runtime crate.
+fn write_facade_crate(
+    shim_dir: &Path,
+    mut manifest: toml_edit::Document,
+    fns: &[wasm::ProcMacroFn],
+    runtime_name: &str,
+) -> Result<(), anyhow::Error> {
+    build::modify_cargo_toml_for_watt(&mut manifest, false);
+    let dependencies = manifest["dependencies"].as_table_mut().unwrap();
+    dependencies.remove("watt");
+    let runtime_path = format!("../{}", runtime_name);
+    dependencies[RUNTIME_CRATE] = toml_edit::value(utils_toml::dependency("path", &runtime_path));
+
+    let name = manifest["package"]["name"].as_str().unwrap().to_string();
+    let statics = quote::quote! { use watt_bundle::{watt, MACRO}; };
+    // the exports of the bundle are namespaced with the major version, unlike the ones `cargo watt watch` builds
+    let lib = build::shim_librs(&name, statics, fns, false);
+
+    let src = shim_dir.join("src");
+    std::fs::create_dir_all(&src)?;
+    std::fs::write(
+        shim_dir.join("Cargo.toml"),
+        manifest.to_string_in_original_order(),
+    )?;
+    std::fs::write(src.join("lib.rs"), lib)?;
+    if let Err(e) = utils::cargo_fmt(shim_dir) {
+        log::warn!("failed to format crate: {}", e);
+    }
+    Ok(())
+}
+
+/// The runtime crate a facade shim in `shim_dir` depends on, if it is one.
+pub fn runtime_of(shim_dir: &Path) -> Option<PathBuf> {
+    let manifest = utils_toml::read(&shim_dir.join("Cargo.toml")).ok()?;
+    let path = manifest["dependencies"][RUNTIME_CRATE]["path"].as_str()?;
+    Some(shim_dir.join(path))
+}
+
+fn is_runtime(dir: &Path) -> bool {
+    dir.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(RUNTIME_CRATE))
+}
+
+/// Moves the runtime crates built in `staging_dir` next to the shims in `watt_crate_dir`.
+pub fn install_runtimes(staging_dir: &Path, watt_crate_dir: &Path) -> Result<(), anyhow::Error> {
+    for entry in std::fs::read_dir(staging_dir)? {
+        let dir = entry?.path();
+        if is_runtime(&dir) {
+            std::fs::create_dir_all(watt_crate_dir)?;
+            utils::move_dir(&dir, &watt_crate_dir.join(dir.file_name().unwrap()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the runtime crates in `watt_crate_dir` which no shim depends on anymore.
+pub fn remove_unused_runtimes(watt_crate_dir: &Path) -> Result<(), anyhow::Error> {
+    let dirs: Vec<PathBuf> = match std::fs::read_dir(watt_crate_dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .collect(),
+        Err(_) => return Ok(()),
+    };
+    let used: Vec<PathBuf> = dirs
+        .iter()
+        .filter(|dir| !is_runtime(dir))
+        .filter_map(|dir| runtime_of(dir)?.canonicalize().ok())
+        .collect();
+    for dir in dirs.iter().filter(|dir| is_runtime(dir)) {
+        if !used.contains(&dir.canonicalize()?) {
+            log::debug!("removing the unused bundle {}", dir.display());
+            std::fs::remove_dir_all(dir)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+//! Every `cargo watt patch` run is recorded in `target/cargo-watt/journal`.
+//! The manifests, `Cargo.lock` and `.watt-patched` are backed up before anything is touched,
+//! so that a failed or interrupted run can be undone, and so can the last finished one with `--rollback`.
+
+use super::WATT_DIR;
+use crate::utils;
+use anyhow::Context;
+use cargo_metadata::Metadata;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+const JOURNAL_FILE: &str = "journal.json";
+
+fn cargo_watt_dir(metadata: &Metadata) -> PathBuf {
+    metadata.target_directory.join("cargo-watt")
+}
+
+struct BackedUpFile {
+    path: PathBuf,
+    existed: bool,
+    /// the hash of the file after the run, to notice changes made since
+    after: Option<String>,
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    let mut hasher = utils::Fnv::new();
+    hasher.write(&std::fs::read(path).ok()?);
+    Some(hasher.finish())
+}
+
+pub struct Journal {
+    dir: PathBuf,
+    staging_dir: PathBuf,
+    workspace_root: PathBuf,
+    files: Vec<BackedUpFile>,
+    watt_dir_existed: bool,
+}
+
+impl Journal {
+    /// Backs up the current state of the workspace, replacing the journal of the previous run.
+    pub fn begin(metadata: &Metadata) -> Result<Journal, anyhow::Error> {
+        let dir = cargo_watt_dir(metadata).join("journal");
+        let staging_dir = cargo_watt_dir(metadata).join("staging");
+        for dir in &[&dir, &staging_dir] {
+            if dir.exists() {
+                std::fs::remove_dir_all(dir)?;
+            }
+        }
+        std::fs::create_dir_all(dir.join("files"))?;
+        std::fs::create_dir_all(&staging_dir)?;
+
+        let workspace_root = metadata.workspace_root.clone();
+        let mut paths = vec![
+            workspace_root.join("Cargo.toml"),
+            workspace_root.join("Cargo.lock"),
+        ];
+        paths.extend(
+            super::member_manifests(metadata)
+                .into_iter()
+                .map(Path::to_path_buf),
+        );
+        paths.sort();
+        paths.dedup();
+
+        let mut files = Vec::new();
+        for (i, path) in paths.into_iter().enumerate() {
+            let existed = path.exists();
+            if existed {
+                std::fs::copy(&path, dir.join("files").join(i.to_string()))?;
+            }
+            files.push(BackedUpFile {
+                path,
+                existed,
+                after: None,
+            });
+        }
+
+        // shims are never modified in place, only replaced, so hard links are enough
+        let watt_dir = workspace_root.join(WATT_DIR);
+        let watt_dir_existed = watt_dir.exists();
+        if watt_dir_existed {
+            utils::link_all(&watt_dir, &dir.join(WATT_DIR))?;
+        }
+
+        let journal = Journal {
+            dir,
+            staging_dir,
+            workspace_root,
+            files,
+            watt_dir_existed,
+        };
+        journal.write(false)?;
+        Ok(journal)
+    }
+
+    /// Reads the journal of the last run, if there is one.
+    pub fn last(metadata: &Metadata) -> Result<Option<(Journal, bool)>, anyhow::Error> {
+        let dir = cargo_watt_dir(metadata).join("journal");
+        let content = match std::fs::read_to_string(dir.join(JOURNAL_FILE)) {
+            Ok(content) => content,
+            Err(_) => return Ok(None),
+        };
+        let value: serde_json::Value =
+            serde_json::from_str(&content).context("failed to parse the journal")?;
+
+        let files = value["files"]
+            .as_array()
+            .context("invalid journal")?
+            .iter()
+            .filter_map(|file| {
+                Some(BackedUpFile {
+                    path: file["path"].as_str()?.into(),
+                    existed: file["existed"].as_bool()?,
+                    after: file["after"].as_str().map(String::from),
+                })
+            })
+            .collect();
+        let journal = Journal {
+            staging_dir: cargo_watt_dir(metadata).join("staging"),
+            dir,
+            workspace_root: metadata.workspace_root.clone(),
+            files,
+            watt_dir_existed: value["watt_dir_existed"].as_bool().unwrap_or(false),
+        };
+        let complete = value["complete"].as_bool().unwrap_or(false);
+        Ok(Some((journal, complete)))
+    }
+
+    fn write(&self, complete: bool) -> Result<(), anyhow::Error> {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|file| json!({ "path": file.path, "existed": file.existed, "after": file.after }))
+            .collect();
+        let journal = json!({
+            "complete": complete,
+            "files": files,
+            "watt_dir_existed": self.watt_dir_existed,
+        });
+        std::fs::write(self.dir.join(JOURNAL_FILE), journal.to_string())?;
+        Ok(())
+    }
+
+    /// Shims are built in here and only moved into `.watt-patched` once all builds are done.
+    pub fn staging_dir(&self) -> &Path {
+        &self.staging_dir
+    }
+
+    /// Marks the run as finished, the backup stays around for `--rollback`.
+    pub fn complete(mut self) -> Result<(), anyhow::Error> {
+        for file in &mut self.files {
+            file.after = hash_file(&file.path);
+        }
+        self.write(true)?;
+        if self.staging_dir.exists() {
+            std::fs::remove_dir_all(&self.staging_dir)?;
+        }
+        Ok(())
+    }
+
+    /// The manifests which have been edited since the run finished, `Cargo.lock` is left out since cargo updates it anyway.
+    pub fn changed_since(&self) -> Vec<&Path> {
+        self.files
+            .iter()
+            .filter(|file| {
+                file.path
+                    .file_name()
+                    .is_some_and(|name| name != "Cargo.lock")
+            })
+            .filter(|file| file.after.is_some() && hash_file(&file.path) != file.after)
+            .map(|file| file.path.as_path())
+            .collect()
+    }
+
+    /// Restores the state from before the run and deletes the journal.
+    pub fn rollback(self) -> Result<(), anyhow::Error> {
+        for (i, file) in self.files.iter().enumerate() {
+            if file.existed {
+                std::fs::copy(self.dir.join("files").join(i.to_string()), &file.path)
+                    .with_context(|| format!("failed to restore {}", file.path.display()))?;
+            } else if file.path.exists() {
+                std::fs::remove_file(&file.path)?;
+            }
+        }
+
+        let watt_dir = self.workspace_root.join(WATT_DIR);
+        if watt_dir.exists() {
+            std::fs::remove_dir_all(&watt_dir)?;
+        }
+        if self.watt_dir_existed {
+            utils::move_dir(&self.dir.join(WATT_DIR), &watt_dir)?;
+        }
+
+        std::fs::remove_dir_all(&self.dir)?;
+        if self.staging_dir.exists() {
+            std::fs::remove_dir_all(&self.staging_dir)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,541 @@
+use super::WATT_DIR;
+use crate::utils_toml;
+use std::path::{Path, PathBuf};
+
+/// The `[patch]` entries added by cargo watt are listed in `[package.metadata.watt]`
+/// (or `[workspace.metadata.watt]` for virtual manifests), so that they can be told apart from the user's own.
+const MARKER_KEY: &str = "patched";
+
+/// Rewritten path dependencies are recorded in `[package.metadata.watt.rewritten.<kind>]` of the member,
+/// together with their original path.
+const REWRITTEN_KEY: &str = "rewritten";
+
+const DEPENDENCY_KINDS: &[&str] = &["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// A `[patch.<table>]` entry, listed as `key` for crates.io and as `table#key` otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PatchKey {
+    pub table: String,
+    pub key: String,
+}
+impl PatchKey {
+    pub fn new(table: &str, key: &str) -> Self {
+        PatchKey {
+            table: table.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s.rsplit_once('#') {
+            Some((table, key)) => PatchKey::new(table, key),
+            None => PatchKey::new("crates-io", s),
+        }
+    }
+
+    pub fn entry<'a>(&self, manifest: &'a toml_edit::Document) -> &'a toml_edit::Item {
+        &manifest["patch"][self.table.as_str()][self.key.as_str()]
+    }
+
+    /// The name of the patched crate, which differs from the key for entries like `name-1 = { package = "name" }`
+    pub fn package<'a>(&'a self, manifest: &'a toml_edit::Document) -> &'a str {
+        self.entry(manifest)["package"]
+            .as_str()
+            .unwrap_or(&self.key)
+    }
+}
+impl std::fmt::Display for PatchKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.table == "crates-io" {
+            write!(f, "{}", self.key)
+        } else {
+            write!(f, "{}#{}", self.table, self.key)
+        }
+    }
+}
+
+fn metadata_root(manifest: &toml_edit::Document) -> &'static str {
+    if manifest["package"].is_none() {
+        "workspace"
+    } else {
+        "package"
+    }
+}
+
+pub fn patched_entries(manifest: &toml_edit::Document) -> Vec<PatchKey> {
+    let root = metadata_root(manifest);
+    manifest[root]["metadata"]["watt"][MARKER_KEY]
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| entry.as_str())
+                .map(PatchKey::parse)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn set_patched_entries(manifest: &mut toml_edit::Document, entries: &[PatchKey]) {
+    let root = metadata_root(manifest);
+    if entries.is_empty() {
+        utils_toml::remove(
+            manifest.as_table_mut(),
+            &[root, "metadata", "watt", MARKER_KEY],
+        );
+        return;
+    }
+
+    let metadata = utils_toml::implicit_table(manifest, root, "metadata");
+    let watt = metadata["watt"]
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .unwrap();
+    let entries: Vec<String> = entries.iter().map(PatchKey::to_string).collect();
+    watt[MARKER_KEY] = toml_edit::value(
+        entries
+            .iter()
+            .map(String::as_str)
+            .collect::<toml_edit::Value>(),
+    );
+}
+
+/// A `[patch]` entry pointing to the shim at `path`.
+pub struct NewPatch {
+    pub key: PatchKey,
+    pub package: String,
+    pub path: String,
+}
+
+/// Adds the `patches` to the manifest and deletes the shims of entries which pointed somewhere else before.
+pub fn add_patches(toml_path: &Path, patches: &[NewPatch]) -> Result<(), anyhow::Error> {
+    let mut manifest = utils_toml::read(toml_path)?;
+    let replaced = insert_patches(&mut manifest, patches);
+
+    let new_toml = manifest.to_string_in_original_order();
+    std::fs::write(toml_path, new_toml)?;
+
+    let root = toml_path.parent().unwrap();
+    for old_path in replaced {
+        let dir = root.join(&old_path);
+        if dir.starts_with(root.join(WATT_DIR)) && dir.exists() {
+            log::info!("removing the outdated shim in {}", old_path);
+            std::fs::remove_dir_all(dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the `patches` to the `[patch]` tables and the list of patched entries.
+/// Returns the paths the replaced entries pointed to.
+fn insert_patches(manifest: &mut toml_edit::Document, patches: &[NewPatch]) -> Vec<String> {
+    let mut replaced = Vec::new();
+    for patch in patches {
+        let table = utils_toml::implicit_table(manifest, "patch", &patch.key.table);
+        let path_str = patch.path.as_str();
+        // keep the formatting of entries which are already there
+        let entry = &mut table[&utils_toml::key(&patch.key.key)];
+        if entry["path"].as_str() == Some(path_str) {
+            continue;
+        }
+        if let Some(old_path) = entry["path"].as_str() {
+            replaced.push(old_path.to_string());
+        }
+
+        let mut dependency = toml_edit::InlineTable::default();
+        if patch.key.key != patch.package {
+            dependency.get_or_insert("package", patch.package.as_str());
+        }
+        dependency.get_or_insert("path", path_str);
+        dependency.fmt();
+        *entry = toml_edit::value(dependency);
+    }
+
+    let mut entries = patched_entries(manifest);
+    entries.extend(patches.iter().map(|patch| patch.key.clone()));
+    entries.sort();
+    entries.dedup();
+    set_patched_entries(manifest, &entries);
+    replaced
+}
+
+/// A path dependency of a workspace member which now points to the shim.
+pub struct Rewrite {
+    pub kind: String,
+    pub key: String,
+    pub package: String,
+    pub original: String,
+}
+
+pub fn rewritten_dependencies(manifest: &toml_edit::Document) -> Vec<Rewrite> {
+    let mut rewrites = Vec::new();
+    for kind in DEPENDENCY_KINDS {
+        let table = &manifest["package"]["metadata"]["watt"][REWRITTEN_KEY][*kind];
+        for (key, original) in table.as_table().into_iter().flat_map(|table| table.iter()) {
+            if let Some(original) = original.as_str() {
+                let package = manifest[*kind][key]["package"].as_str().unwrap_or(key);
+                rewrites.push(Rewrite {
+                    kind: kind.to_string(),
+                    key: key.to_string(),
+                    package: package.to_string(),
+                    original: original.to_string(),
+                });
+            }
+        }
+    }
+    rewrites
+}
+
+/// Points the path dependencies on `original_dir` in the member's manifest to `shim_dir`.
+/// Returns whether there were any.
+pub fn rewrite_path_dependencies(
+    toml_path: &Path,
+    package: &str,
+    original_dir: &Path,
+    shim_dir: &Path,
+) -> Result<bool, anyhow::Error> {
+    let mut manifest = utils_toml::read(toml_path)?;
+    let member_dir = toml_path.parent().unwrap().canonicalize()?;
+    let original_dir = original_dir.canonicalize()?;
+    let new_path = crate::utils::relative_path(&member_dir, shim_dir);
+
+    let points_to_original = |path: &str| {
+        member_dir
+            .join(path)
+            .canonicalize()
+            .is_ok_and(|dir| dir == original_dir)
+    };
+    let rewritten = rewrite_dependencies(
+        &mut manifest,
+        package,
+        points_to_original,
+        &new_path.to_string_lossy(),
+    );
+
+    if rewritten {
+        std::fs::write(toml_path, manifest.to_string_in_original_order())?;
+    }
+    Ok(rewritten)
+}
+
+/// Points the path dependencies on `package` for which `points_to_original` holds to `new_path`
+/// and records their original path.
+fn rewrite_dependencies(
+    manifest: &mut toml_edit::Document,
+    package: &str,
+    points_to_original: impl Fn(&str) -> bool,
+    new_path: &str,
+) -> bool {
+    let mut rewritten = Vec::new();
+    for kind in DEPENDENCY_KINDS {
+        let table = match manifest[*kind].as_table() {
+            Some(table) => table,
+            None => continue,
+        };
+        for (key, dependency) in table.iter() {
+            let path = match dependency["path"].as_str() {
+                Some(path) => path,
+                None => continue,
+            };
+            let is_package = dependency["package"].as_str().unwrap_or(key) == package;
+            if is_package && points_to_original(path) {
+                rewritten.push((kind.to_string(), key.to_string(), path.to_string()));
+            }
+        }
+    }
+
+    for (kind, key, original) in &rewritten {
+        utils_toml::set_path(
+            &mut manifest[kind.as_str()][utils_toml::key(key).as_str()],
+            new_path,
+        );
+        let recorded = utils_toml::implicit_tables(
+            manifest.as_table_mut(),
+            &["package", "metadata", "watt", REWRITTEN_KEY, kind],
+        );
+        recorded[&utils_toml::key(key)] = toml_edit::value(original.as_str());
+    }
+    !rewritten.is_empty()
+}
+
+/// Restores the original paths of the rewritten dependencies on one of `crates` (or all, if empty)
+/// and returns the shim directories they pointed to.
+pub fn restore_path_dependencies(
+    toml_path: &Path,
+    crates: &[String],
+    level: log::Level,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut manifest = utils_toml::read(toml_path)?;
+    let member_dir = toml_path.parent().unwrap();
+
+    let restored = restore_dependencies(&mut manifest, crates);
+    for (key, _) in &restored {
+        log::log!(
+            level,
+            "restored path dependency '{}' in {}",
+            key,
+            toml_path.display()
+        );
+    }
+
+    let shim_dirs: Vec<PathBuf> = restored
+        .iter()
+        .filter_map(|(_, shim_path)| shim_path.as_ref())
+        .map(|shim_path| member_dir.join(shim_path))
+        .collect();
+    if !shim_dirs.is_empty() {
+        std::fs::write(toml_path, manifest.to_string_in_original_order())?;
+    }
+    Ok(shim_dirs)
+}
+
+/// Restores the rewritten dependencies on one of `crates` (or all, if empty).
+/// Returns their keys and the paths of the shims they pointed to.
+fn restore_dependencies(
+    manifest: &mut toml_edit::Document,
+    crates: &[String],
+) -> Vec<(String, Option<String>)> {
+    let mut restored = Vec::new();
+    for rewrite in rewritten_dependencies(manifest) {
+        let matches = crates
+            .iter()
+            .any(|name| *name == rewrite.key || *name == rewrite.package);
+        if !crates.is_empty() && !matches {
+            continue;
+        }
+
+        let dependency =
+            &mut manifest[rewrite.kind.as_str()][utils_toml::key(&rewrite.key).as_str()];
+        let shim_path = dependency["path"].as_str().map(str::to_string);
+        utils_toml::set_path(dependency, &rewrite.original);
+        utils_toml::remove(
+            manifest.as_table_mut(),
+            &[
+                "package",
+                "metadata",
+                "watt",
+                REWRITTEN_KEY,
+                &rewrite.kind,
+                &rewrite.key,
+            ],
+        );
+        restored.push((rewrite.key, shim_path));
+    }
+    restored
+}
+
+/// Makes the path dependencies and `[patch]` entries in `toml_path`, a copy of the manifest in `original_dir`,
+/// absolute if they point outside of `original_root`, so that they still work from the copy of the workspace.
+pub fn absolutize_path_dependencies(
+    toml_path: &Path,
+    original_dir: &Path,
+    original_root: &Path,
+) -> Result<(), anyhow::Error> {
+    let mut manifest = utils_toml::read(toml_path)?;
+    let original_root = original_root.canonicalize()?;
+    let outside = |path: &str| {
+        original_dir
+            .join(path)
+            .canonicalize()
+            .ok()
+            .filter(|dir| !dir.starts_with(&original_root))
+    };
+
+    let mut tables: Vec<Vec<&str>> = DEPENDENCY_KINDS.iter().map(|kind| vec![*kind]).collect();
+    if let Some(patch) = manifest["patch"].as_table() {
+        tables.extend(patch.iter().map(|(table, _)| vec!["patch", table]));
+    }
+
+    let mut absolutized = Vec::new();
+    for keys in tables {
+        let table = keys.iter().fold(&manifest.root, |item, key| &item[*key]);
+        for (key, dependency) in table.as_table().into_iter().flat_map(|table| table.iter()) {
+            if let Some(dir) = dependency["path"].as_str().and_then(outside) {
+                let mut keys: Vec<String> = keys.iter().map(|key| utils_toml::key(key)).collect();
+                keys.push(utils_toml::key(key));
+                absolutized.push((keys, dir));
+            }
+        }
+    }
+
+    for (keys, dir) in &absolutized {
+        let dependency = keys
+            .iter()
+            .fold(&mut manifest.root, |item, key| &mut item[key.as_str()]);
+        dependency["path"] = toml_edit::value(dir.to_string_lossy().as_ref());
+    }
+    if !absolutized.is_empty() {
+        std::fs::write(toml_path, manifest.to_string_in_original_order())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(toml: &str) -> toml_edit::Document {
+        toml.parse().unwrap()
+    }
+
+    fn new_patch(key: &str, package: &str, path: &str) -> NewPatch {
+        NewPatch {
+            key: PatchKey::new("crates-io", key),
+            package: package.to_string(),
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn patch_two_versions() {
+        let mut manifest = manifest("[package]\nname = \"consumer\"\n");
+        let patches = [
+            new_patch(
+                "my-macros-0-1",
+                "my-macros",
+                "./.watt-patched/my-macros-0.1.0",
+            ),
+            new_patch(
+                "my-macros-1",
+                "my-macros",
+                "./.watt-patched/my-macros-1.0.0",
+            ),
+        ];
+        assert!(insert_patches(&mut manifest, &patches).is_empty());
+
+        let entries = patched_entries(&manifest);
+        assert_eq!(
+            entries,
+            [
+                PatchKey::new("crates-io", "my-macros-0-1"),
+                PatchKey::new("crates-io", "my-macros-1"),
+            ]
+        );
+        for key in &entries {
+            assert_eq!(key.package(&manifest), "my-macros");
+        }
+        assert_eq!(
+            entries[1].entry(&manifest)["path"].as_str(),
+            Some("./.watt-patched/my-macros-1.0.0")
+        );
+        assert_eq!(
+            manifest["package"]["metadata"]["watt"]["patched"]
+                .as_array()
+                .map(|entries| entries.len()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn patch_again_replaces_shim() {
+        let mut manifest = manifest("[package]\nname = \"consumer\"\n");
+        let old = new_patch("my-macros", "my-macros", "./.watt-patched/my-macros-1.0.0");
+        let new = new_patch("my-macros", "my-macros", "./.watt-patched/my-macros-1.0.1");
+        insert_patches(&mut manifest, &[old]);
+        let replaced = insert_patches(&mut manifest, &[new]);
+
+        assert_eq!(replaced, ["./.watt-patched/my-macros-1.0.0"]);
+        assert_eq!(
+            patched_entries(&manifest),
+            [PatchKey::new("crates-io", "my-macros")]
+        );
+        // no `package` for entries which aren't renamed
+        assert!(manifest["patch"]["crates-io"]["my-macros"]["package"].is_none());
+    }
+
+    #[test]
+    fn patched_entries_of_virtual_manifest() {
+        let mut manifest = manifest("[workspace]\nmembers = [\"a\"]\n");
+        let keys = [
+            PatchKey::new("crates-io", "my-macros"),
+            PatchKey::new("https://github.com/owner/repo", "other-macros"),
+        ];
+        set_patched_entries(&mut manifest, &keys);
+        assert_eq!(
+            manifest["workspace"]["metadata"]["watt"]["patched"][1].as_str(),
+            Some("https://github.com/owner/repo#other-macros")
+        );
+        assert_eq!(patched_entries(&manifest), keys);
+
+        set_patched_entries(&mut manifest, &[]);
+        assert!(manifest["workspace"]["metadata"].is_none());
+        assert!(patched_entries(&manifest).is_empty());
+    }
+
+    #[test]
+    fn rewrite_and_restore_path_dependency() {
+        let original = r#"[package]
+name = "consumer"
+
+[dependencies]
+macros = { package = "my-macros", path = "../my-macros" }
+other = { path = "../other" }
+
+[dev-dependencies]
+my-macros = { path = "../my-macros" }
+"#;
+        let mut manifest = manifest(original);
+        let rewritten = rewrite_dependencies(
+            &mut manifest,
+            "my-macros",
+            |path| path == "../my-macros",
+            "../.watt-patched/my-macros-1.0.0",
+        );
+        assert!(rewritten);
+        assert_eq!(
+            manifest["dependencies"]["macros"]["path"].as_str(),
+            Some("../.watt-patched/my-macros-1.0.0")
+        );
+        assert_eq!(
+            manifest["dependencies"]["other"]["path"].as_str(),
+            Some("../other")
+        );
+
+        let rewrites = rewritten_dependencies(&manifest);
+        let rewrites: Vec<_> = rewrites
+            .iter()
+            .map(|r| {
+                (
+                    r.kind.as_str(),
+                    r.key.as_str(),
+                    r.package.as_str(),
+                    r.original.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rewrites,
+            [
+                ("dependencies", "macros", "my-macros", "../my-macros"),
+                ("dev-dependencies", "my-macros", "my-macros", "../my-macros"),
+            ]
+        );
+
+        // by the name of the package, which also restores the renamed dependency
+        let restored = restore_dependencies(&mut manifest, &["my-macros".to_string()]);
+        assert_eq!(restored.len(), 2);
+        assert!(restored
+            .iter()
+            .all(|(_, shim)| shim.as_deref() == Some("../.watt-patched/my-macros-1.0.0")));
+        assert!(rewritten_dependencies(&manifest).is_empty());
+        assert_eq!(manifest.to_string_in_original_order(), original);
+    }
+
+    #[test]
+    fn restore_only_given_crates() {
+        let mut manifest = manifest(
+            "[package]\nname = \"consumer\"\n\n[dependencies]\na = { path = \"../a\" }\nb = { path = \"../b\" }\n",
+        );
+        rewrite_dependencies(&mut manifest, "a", |_| true, "../.watt-patched/a-1.0.0");
+        rewrite_dependencies(&mut manifest, "b", |_| true, "../.watt-patched/b-1.0.0");
+
+        let restored = restore_dependencies(&mut manifest, &["b".to_string()]);
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].0, "b");
+        assert_eq!(manifest["dependencies"]["b"]["path"].as_str(), Some("../b"));
+        let remaining = rewritten_dependencies(&manifest);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].key, "a");
+    }
+}
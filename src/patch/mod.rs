@@ -0,0 +1,1064 @@
+use crate::{
+    report::{self, ErrorCode, Event},
+    utils::human_size,
+    CompilationOptions, PackageSelection, PatchOptions,
+};
+use anyhow::Context;
+use cargo_metadata::{CargoOpt, DependencyKind, Metadata, MetadataCommand, Package, PackageId};
+use rayon::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+pub mod bundle;
+mod journal;
+mod manifest;
+mod source;
+pub mod store;
+mod target;
+mod trial;
+
+pub use bundle::remove_unused_runtimes;
+pub use manifest::{
+    patched_entries, restore_path_dependencies, rewritten_dependencies, set_patched_entries,
+    PatchKey,
+};
+pub use source::Source;
+pub use trial::{copy_project, unpatch_copy};
+
+use journal::Journal;
+use manifest::{add_patches, rewrite_path_dependencies, NewPatch};
+use target::WasmTargets;
+use trial::Trial;
+
+pub const WATT_DIR: &str = ".watt-patched";
+
+pub fn is_proc_macro(package: &Package) -> bool {
+    package
+        .targets
+        .iter()
+        .any(|target| target.kind.iter().any(|kind| kind == "proc-macro"))
+}
+
+/// Shims go into `.watt-patched/<name>-<version>`, so that several versions of a macro can be patched at once.
+fn shim_dir_name(package: &Package) -> String {
+    format!("{}-{}", package.name, package.version)
+}
+
+/// The part of the version which semver-compatible versions share, e.g. `1` for 1.0.130 and `0-2` for 0.2.6.
+pub fn compatible_version(version: &cargo_metadata::Version) -> String {
+    match (version.major, version.minor) {
+        (0, 0) => format!("0-0-{}", version.patch),
+        (0, minor) => format!("0-{}", minor),
+        (major, _) => major.to_string(),
+    }
+}
+
+/// The key of the `[patch]` entry for `package`. If the project depends on several versions of the crate,
+/// the entries are renamed to `<name>-<compatible version>` with `package = "<name>"`, since keys have to be unique.
+fn patch_key_name(package: &Package, macros: &[&Package]) -> String {
+    let several_versions = macros
+        .iter()
+        .any(|other| other.name == package.name && other.version != package.version);
+    if several_versions {
+        format!("{}-{}", package.name, compatible_version(&package.version))
+    } else {
+        package.name.clone()
+    }
+}
+
+/// A proc macro together with the directory of its shim.
+type Shim<'a> = (&'a Package, PathBuf);
+
+/// Whether `package` is a shim, which is what cargo metadata returns for macros once the patch is in use.
+fn is_shim(package: &Package, watt_crate_dir: &Path) -> bool {
+    package.manifest_path.starts_with(watt_crate_dir) || store::contains(&package.manifest_path)
+}
+
+/// The source `package` was originally fetched from, which for shims in use is recorded in the shim.
+fn original_source(package: &Package, watt_crate_dir: &Path) -> Option<Source> {
+    if is_shim(package, watt_crate_dir) {
+        ShimInfo::read(package.manifest_path.parent()?).map(|shim| shim.source)
+    } else {
+        Source::of(package)
+    }
+}
+
+pub enum Outcome {
+    Patched {
+        size: usize,
+        duration: Duration,
+    },
+    /// the existing shim was built from the same source with the same options
+    Fresh {
+        size: usize,
+    },
+    Failed {
+        error: anyhow::Error,
+        duration: Duration,
+    },
+    Skipped {
+        reason: &'static str,
+    },
+}
+
+/// Runs `cargo metadata` without resolving dependencies, which works even when patches are broken.
+pub fn workspace(path: &Path) -> Result<Metadata, anyhow::Error> {
+    MetadataCommand::new()
+        .current_dir(path)
+        .no_deps()
+        .exec()
+        .context(ErrorCode::InvalidManifest)
+}
+
+/// `[patch]` is only honored in the root manifest of a workspace, so that's where everything goes.
+pub fn workspace_root(path: &Path) -> Result<PathBuf, anyhow::Error> {
+    Ok(workspace(path)?.workspace_root)
+}
+
+fn member_manifests(metadata: &Metadata) -> Vec<&Path> {
+    metadata
+        .workspace_members
+        .iter()
+        .map(|id| metadata[id].manifest_path.as_path())
+        .collect()
+}
+
+/// Returns the workspace members chosen by `--package` and `--exclude`.
+pub fn selected_members<'a>(
+    metadata: &'a Metadata,
+    selection: &PackageSelection,
+) -> Result<Vec<&'a PackageId>, anyhow::Error> {
+    let name_of = |id: &PackageId| metadata[id].name.as_str();
+    for name in selection.package.iter().chain(&selection.exclude) {
+        anyhow::ensure!(
+            metadata
+                .workspace_members
+                .iter()
+                .any(|id| name_of(id) == name),
+            "package '{}' is not a member of the workspace",
+            name
+        );
+    }
+
+    Ok(metadata
+        .workspace_members
+        .iter()
+        .filter(|id| {
+            selection.package.is_empty() || selection.package.iter().any(|p| p == name_of(id))
+        })
+        .filter(|id| !selection.exclude.iter().any(|p| p == name_of(id)))
+        .collect())
+}
+
+/// Returns all packages the `members` depend on, directly or transitively.
+/// With `no_dev`, dev-dependencies of the members are not followed.
+pub fn reachable_packages<'a>(
+    metadata: &'a Metadata,
+    members: &[&'a PackageId],
+    no_dev: bool,
+) -> Result<Vec<&'a Package>, anyhow::Error> {
+    let resolve = metadata
+        .resolve
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("cargo metadata didn't resolve dependencies"))?;
+    let nodes: HashMap<_, _> = resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+
+    let mut visited: HashSet<&PackageId> = HashSet::new();
+    let mut stack = members.to_vec();
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        let is_member = members.contains(&id);
+        for dep in &nodes[id].deps {
+            let dev_only = dep
+                .dep_kinds
+                .iter()
+                .all(|info| info.kind == DependencyKind::Development);
+            if no_dev && is_member && dev_only {
+                continue;
+            }
+            stack.push(&dep.pkg);
+        }
+    }
+
+    Ok(metadata
+        .packages
+        .iter()
+        .filter(|package| visited.contains(&package.id))
+        .collect())
+}
+
+/// What a shim in `.watt-patched` was built from, stored in its `[package.metadata.watt]`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ShimInfo {
+    pub version: String,
+    pub source: Source,
+    pub checksum: Option<String>,
+    pub options: Vec<String>,
+}
+impl ShimInfo {
+    pub fn read(shim_dir: &Path) -> Option<ShimInfo> {
+        let manifest = crate::utils_toml::read(&shim_dir.join("Cargo.toml")).ok()?;
+        let watt = &manifest["package"]["metadata"]["watt"];
+        Some(ShimInfo {
+            version: watt["version"].as_str()?.to_string(),
+            // shims built before other sources were supported are from crates.io
+            source: match watt["source"].as_str() {
+                Some(source) => Source::parse(source)?,
+                None => Source::CratesIo,
+            },
+            checksum: watt["checksum"].as_str().map(String::from),
+            options: watt["options"]
+                .as_array()?
+                .iter()
+                .filter_map(|option| option.as_str())
+                .map(String::from)
+                .collect(),
+        })
+    }
+
+    fn write(&self, shim_dir: &Path) -> Result<(), anyhow::Error> {
+        let toml_path = shim_dir.join("Cargo.toml");
+        let mut manifest = crate::utils_toml::read(&toml_path)?;
+
+        let metadata = crate::utils_toml::implicit_table(&mut manifest, "package", "metadata");
+        let mut watt = toml_edit::Table::new();
+        watt["version"] = toml_edit::value(self.version.as_str());
+        watt["source"] = toml_edit::value(self.source.to_string());
+        if let Some(checksum) = &self.checksum {
+            watt["checksum"] = toml_edit::value(checksum.as_str());
+        }
+        watt["options"] = toml_edit::value(
+            self.options
+                .iter()
+                .map(String::as_str)
+                .collect::<toml_edit::Value>(),
+        );
+        metadata["watt"] = toml_edit::Item::Table(watt);
+
+        std::fs::write(toml_path, manifest.to_string_in_original_order())?;
+        Ok(())
+    }
+}
+
+/// Returns the size of the wasm file in the shim crate.
+pub fn wasm_size(shim_dir: &Path) -> Option<u64> {
+    // the shims of a bundle share the wasm file of its runtime crate
+    if let Some(runtime_dir) = bundle::runtime_of(shim_dir) {
+        return wasm_size(&runtime_dir);
+    }
+    std::fs::read_dir(shim_dir.join("src"))
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|file| {
+            let name = file.file_name();
+            let name = name.to_string_lossy();
+            name.ends_with(".wasm") || name.ends_with(".wasm.deflate")
+        })
+        .and_then(|file| file.metadata().ok())
+        .map(|metadata| metadata.len())
+}
+
+/// Reads the checksums of all crates.io packages from `Cargo.lock`, keyed by name and version.
+fn lockfile_checksums(workspace_root: &Path) -> HashMap<(String, String), String> {
+    let lockfile = match crate::utils_toml::read(&workspace_root.join("Cargo.lock")) {
+        Ok(lockfile) => lockfile,
+        Err(_) => return HashMap::new(),
+    };
+    let packages = match lockfile["package"].as_array_of_tables() {
+        Some(packages) => packages,
+        None => return HashMap::new(),
+    };
+
+    packages
+        .iter()
+        .filter_map(|package| {
+            let name = package["name"].as_str()?;
+            let version = package["version"].as_str()?;
+            let checksum = package["checksum"].as_str()?;
+            Some((
+                (name.to_string(), version.to_string()),
+                checksum.to_string(),
+            ))
+        })
+        .collect()
+}
+
+fn build_patched_crate(
+    package: &Package,
+    shim: &ShimInfo,
+    shim_dir: &Path,
+    checkout: Option<&Path>,
+    compilation_options: &CompilationOptions,
+    targets: &WasmTargets,
+) -> Result<usize, anyhow::Error> {
+    // build exactly the locked version, otherwise cargo would ignore the patch
+    let tempdir = shim.source.fetch(
+        &package.name,
+        &shim.version,
+        shim.checksum.as_deref(),
+        checkout,
+    )?;
+
+    let lock = targets.lock()?;
+    let size = crate::build::build(
+        &tempdir,
+        Some(shim_dir.to_path_buf()),
+        compilation_options,
+        true,
+        true,
+        Some(&lock.target_dir()),
+        false,
+    )?;
+    shim.write(shim_dir)
+        .context("failed to record the source of the shim")?;
+
+    Ok(size)
+}
+
+/// Unless the shim is already in use, cargo has checked out git dependencies next to the manifest.
+fn checkout<'a>(package: &'a Package, watt_crate_dir: &Path) -> Option<&'a Path> {
+    match is_shim(package, watt_crate_dir) {
+        true => None,
+        false => package.manifest_path.parent(),
+    }
+}
+
+/// Determines which source the shim for `package` should be built from, or why it shouldn't be built.
+fn wanted_shim(
+    package: &Package,
+    metadata: &Metadata,
+    watt_crate_dir: &Path,
+    checksums: &HashMap<(String, String), String>,
+    options: Vec<String>,
+) -> Result<ShimInfo, &'static str> {
+    // Shims without recorded source were built by an older cargo watt from the version they still carry
+    if is_shim(package, watt_crate_dir) {
+        let shim_dir = package.manifest_path.parent().unwrap();
+        let existing = ShimInfo::read(shim_dir).unwrap_or_else(|| ShimInfo {
+            version: package.version.to_string(),
+            source: Source::CratesIo,
+            checksum: None,
+            options: Vec::new(),
+        });
+        // local sources can change without a new version
+        let checksum = match &existing.source {
+            Source::Path(_) => existing.source.checksum(),
+            _ => existing.checksum,
+        };
+        return Ok(ShimInfo {
+            checksum,
+            options,
+            ..existing
+        });
+    }
+
+    if metadata.workspace_members.contains(&package.id) {
+        return Err("workspace member");
+    }
+    if package.dependencies.iter().any(|dep| dep.name == "watt") {
+        return Err("already a watt crate");
+    }
+    let source = Source::of(package).ok_or("registry other than crates.io")?;
+
+    let version = package.version.to_string();
+    let checksum = match source {
+        Source::CratesIo => checksums
+            .get(&(package.name.clone(), version.clone()))
+            .cloned(),
+        _ => source.checksum(),
+    };
+    Ok(ShimInfo {
+        version,
+        source,
+        checksum,
+        options,
+    })
+}
+
+fn print_summary(outcomes: &[(&Package, Outcome)]) {
+    eprintln!();
+    eprintln!(
+        "{:<30} {:<10} {:<10} {:>10} {:>8}  reason",
+        "crate", "version", "status", "size", "time"
+    );
+    for (package, outcome) in outcomes {
+        let (status, size, duration, reason) = match outcome {
+            Outcome::Patched { size, duration } => {
+                ("patched", human_size(*size as u64), Some(duration), None)
+            }
+            Outcome::Fresh { size } => ("fresh", human_size(*size as u64), None, None),
+            Outcome::Failed { error, duration } => {
+                let code = ErrorCode::of(error).map_or("unknown", ErrorCode::as_str);
+                let reason = format!("{}: {}", code, error.root_cause());
+                ("failed", String::new(), Some(duration), Some(reason))
+            }
+            Outcome::Skipped { reason } => {
+                ("skipped", String::new(), None, Some(reason.to_string()))
+            }
+        };
+        let duration = duration.map_or(String::new(), |d| format!("{:.1}s", d.as_secs_f32()));
+        eprintln!(
+            "{:<30} {:<10} {:<10} {:>10} {:>8}  {}",
+            package.name,
+            package.version,
+            status,
+            size,
+            duration,
+            reason.unwrap_or_default()
+        );
+    }
+}
+
+/// Removes patches whose shim has been deleted, e.g. by a failed rebuild, since cargo can't resolve them.
+fn remove_missing_shims(workspace_root: &Path) -> Result<(), anyhow::Error> {
+    let workspace = workspace(workspace_root)?;
+    let manifest = crate::utils_toml::read(&workspace_root.join("Cargo.toml"))
+        .context(ErrorCode::InvalidManifest)?;
+
+    let mut missing: Vec<String> = patched_entries(&manifest)
+        .into_iter()
+        .filter(|patch_key| {
+            let path = patch_key.entry(&manifest)["path"].as_str();
+            path.is_some_and(|path| !workspace_root.join(path).join("Cargo.toml").exists())
+        })
+        .map(|patch_key| patch_key.key)
+        .collect();
+    for toml_path in member_manifests(&workspace) {
+        let member = crate::utils_toml::read(toml_path).context(ErrorCode::InvalidManifest)?;
+        let member_dir = toml_path.parent().unwrap();
+        missing.extend(
+            rewritten_dependencies(&member)
+                .into_iter()
+                .filter(|rewrite| {
+                    let path = member[rewrite.kind.as_str()][rewrite.key.as_str()]["path"].as_str();
+                    path.is_some_and(|path| !member_dir.join(path).join("Cargo.toml").exists())
+                })
+                .map(|rewrite| rewrite.key),
+        );
+    }
+
+    if !missing.is_empty() {
+        crate::unpatch::unpatch(workspace_root, &missing)?;
+    }
+    Ok(())
+}
+
+/// Patches the project, restoring its previous state if anything fails or the run is interrupted with Ctrl-C.
+pub fn patch(
+    path: &Path,
+    compilation_options: &CompilationOptions,
+    selection: &PackageSelection,
+    options: &PatchOptions,
+) -> Result<(), anyhow::Error> {
+    let journal = Journal::begin(&workspace(path)?)
+        .context("failed to back up the project")
+        .context(ErrorCode::PatchFailed)?;
+
+    // the cargo processes get the signal as well, so the builds stop right away
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let signal = signal_hook::flag::register(signal_hook::consts::SIGINT, interrupted.clone())?;
+    let result = patch_journaled(
+        path,
+        compilation_options,
+        selection,
+        options,
+        &journal,
+        &interrupted,
+    );
+    signal_hook::low_level::unregister(signal);
+
+    match result {
+        Ok(failed) => {
+            journal.complete()?;
+            // with --keep-going, the other crates stay patched, but the run still counts as failed
+            if failed.is_empty() {
+                return Ok(());
+            }
+            let error = anyhow::anyhow!("failed to build {}", failed.join(", "));
+            Err(error.context(ErrorCode::PatchFailed))
+        }
+        Err(error) => {
+            log::info!("rolling back the changes...");
+            if let Err(rollback_error) = journal.rollback() {
+                log::error!("failed to roll back the changes: {:?}", rollback_error);
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Undoes the last run of `cargo watt patch`.
+pub fn rollback(path: &Path) -> Result<(), anyhow::Error> {
+    let workspace = workspace(path)?;
+    let (journal, complete) =
+        Journal::last(&workspace)?.context("there is no run of `cargo watt patch` to roll back")?;
+
+    if !complete {
+        log::warn!("the last run didn't finish, restoring the state from before it");
+    }
+    let changed = journal.changed_since();
+    if !changed.is_empty() {
+        let changed: Vec<_> = changed
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        anyhow::bail!(
+            "{} changed since the last run, rolling back would discard those changes",
+            changed.join(", ")
+        );
+    }
+
+    journal.rollback()?;
+    log::info!("rolled back the last run of `cargo watt patch`");
+    Ok(())
+}
+
+fn interrupted_error() -> anyhow::Error {
+    anyhow::anyhow!("received Ctrl-C").context(ErrorCode::Interrupted)
+}
+
+/// Builds all macros which aren't up to date into one bundle, see [`bundle`].
+fn build_bundle<'a>(
+    planned: Vec<(&'a Package, Result<ShimInfo, Outcome>, Option<PathBuf>)>,
+    watt_crate_dir: &Path,
+    journal: &Journal,
+    compilation_options: &CompilationOptions,
+    targets: &WasmTargets,
+    interrupted: &AtomicBool,
+) -> Vec<(&'a Package, Outcome, Option<PathBuf>)> {
+    let mut results = Vec::new();
+    let mut members = Vec::new();
+    for (package, plan, shim_dir) in planned {
+        match plan {
+            Err(outcome) => results.push((package, outcome, shim_dir)),
+            Ok(shim) => members.push((
+                bundle::Member {
+                    package,
+                    shim,
+                    checkout: checkout(package, watt_crate_dir),
+                },
+                shim_dir,
+            )),
+        }
+    }
+    if members.is_empty() {
+        return results;
+    }
+
+    if interrupted.load(Ordering::SeqCst) {
+        for (member, shim_dir) in members {
+            let reason = "interrupted";
+            results.push((member.package, Outcome::Skipped { reason }, shim_dir));
+        }
+        return results;
+    }
+
+    let instant = Instant::now();
+    let bundled: Vec<&bundle::Member> = members.iter().map(|(member, _)| member).collect();
+    let built = targets.lock().and_then(|lock| {
+        bundle::build(
+            &bundled,
+            journal.staging_dir(),
+            compilation_options,
+            &lock.target_dir(),
+        )
+    });
+    match built {
+        Ok(size) => {
+            log::info!(
+                "bundled {} crates into {}",
+                members.len(),
+                human_size(size as u64)
+            );
+            // like the error, the size of the whole bundle goes to the first crate, so that the sizes add up
+            let mut size = Some(size);
+            for (member, shim_dir) in members {
+                let size = size.take().unwrap_or(0);
+                let duration = instant.elapsed();
+                results.push((
+                    member.package,
+                    Outcome::Patched { size, duration },
+                    shim_dir,
+                ));
+            }
+        }
+        Err(error) => {
+            // the whole error goes to the first crate, the others refer to it
+            let first = members[0].0.package.name.clone();
+            let mut error = Some(error.context("failed to build the bundle"));
+            for (member, shim_dir) in members {
+                let error = error.take().unwrap_or_else(|| {
+                    anyhow::anyhow!("failed to build the bundle, see '{}'", first)
+                        .context(ErrorCode::CompileFailed)
+                });
+                let duration = instant.elapsed();
+                results.push((
+                    member.package,
+                    Outcome::Failed { error, duration },
+                    shim_dir,
+                ));
+            }
+        }
+    }
+    results
+}
+
+/// Returns the crates which failed to build, which can only happen with `--keep-going`.
+fn patch_journaled(
+    path: &Path,
+    compilation_options: &CompilationOptions,
+    selection: &PackageSelection,
+    options: &PatchOptions,
+    journal: &Journal,
+    interrupted: &AtomicBool,
+) -> Result<Vec<String>, anyhow::Error> {
+    let keep_going = options.keep_going;
+    remove_missing_shims(&workspace_root(path)?)?;
+
+    let metadata = MetadataCommand::new()
+        .current_dir(path)
+        .features(CargoOpt::AllFeatures)
+        .exec()?;
+    let watt_crate_dir = metadata.workspace_root.join(WATT_DIR);
+    let members = selected_members(&metadata, selection)?;
+    let packages = reachable_packages(&metadata, &members, selection.no_dev)?;
+    let checksums = lockfile_checksums(&metadata.workspace_root);
+    let store = match options.global_store {
+        true => Some(
+            store::shims_dir()
+                .context("no cache directory for the global store, set $CARGO_WATT_CACHE_DIR")?,
+        ),
+        false => None,
+    };
+
+    // every macro of the workspace, also the ones not selected, to decide which patch keys need a version
+    let all_members: Vec<_> = metadata.workspace_members.iter().collect();
+    let all_macros: Vec<&Package> = reachable_packages(&metadata, &all_members, false)?
+        .into_iter()
+        .filter(|package| is_proc_macro(package))
+        .collect();
+
+    // without --keep-going, the first failure aborts all builds which haven't started yet
+    let aborted = AtomicBool::new(false);
+
+    anyhow::ensure!(
+        !(options.bundle && options.global_store),
+        "bundles can't be put into the global store"
+    );
+    let jobs = options.jobs.unwrap_or_else(target::default_jobs);
+    anyhow::ensure!(jobs > 0, "--jobs needs to be at least 1");
+    let targets = WasmTargets::new(&metadata, jobs)?;
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    // a bundle facade must not count as fresh when patching without --bundle, and vice versa
+    let mut fingerprint = compilation_options.fingerprint();
+    if options.bundle {
+        fingerprint.push("--bundle".to_string());
+    }
+
+    // decide what to do with every macro, the builds themselves come afterwards
+    let planned: Vec<(&Package, Result<ShimInfo, Outcome>, Option<PathBuf>)> = packages
+        .into_par_iter()
+        .filter(|package| is_proc_macro(package))
+        .map(|package: &Package| {
+            let wanted = wanted_shim(
+                package,
+                &metadata,
+                &watt_crate_dir,
+                &checksums,
+                fingerprint.clone(),
+            );
+            let shim_dir = wanted.as_ref().ok().map(|wanted| match &store {
+                Some(store) => store::shim_dir(store, package, wanted),
+                None => watt_crate_dir.join(shim_dir_name(package)),
+            });
+
+            let plan = match (wanted, &shim_dir) {
+                (Err(reason), _) => Err(Outcome::Skipped { reason }),
+                (Ok(wanted), Some(shim_dir))
+                    if !options.force && ShimInfo::read(shim_dir).as_ref() == Some(&wanted) =>
+                {
+                    log::info!("'{}' {} is up to date", package.name, package.version);
+                    Err(Outcome::Fresh {
+                        size: wasm_size(shim_dir).unwrap_or(0) as usize,
+                    })
+                }
+                (Ok(wanted), _) => Ok(wanted),
+            };
+            (package, plan, shim_dir)
+        })
+        .collect();
+
+    let results: Vec<(&Package, Outcome, Option<PathBuf>)> = if options.bundle {
+        build_bundle(
+            planned,
+            &watt_crate_dir,
+            journal,
+            compilation_options,
+            &targets,
+            interrupted,
+        )
+    } else {
+        pool.install(|| {
+            planned
+                .into_par_iter()
+                .map(|(package, plan, shim_dir)| {
+                    let outcome = match plan {
+                        Err(outcome) => outcome,
+                        Ok(_) if interrupted.load(Ordering::SeqCst) => Outcome::Skipped {
+                            reason: "interrupted",
+                        },
+                        Ok(_) if aborted.load(Ordering::SeqCst) => Outcome::Skipped {
+                            reason: "aborted after a previous failure",
+                        },
+                        Ok(wanted) => {
+                            let instant = Instant::now();
+                            let staged_dir = journal.staging_dir().join(shim_dir_name(package));
+                            let built = build_patched_crate(
+                                package,
+                                &wanted,
+                                &staged_dir,
+                                checkout(package, &watt_crate_dir),
+                                compilation_options,
+                                &targets,
+                            );
+                            match built.with_context(|| {
+                                format!(
+                                    "failed to build crate {} {}",
+                                    package.name, package.version
+                                )
+                            }) {
+                                Ok(size) => Outcome::Patched {
+                                    size,
+                                    duration: instant.elapsed(),
+                                },
+                                Err(error) => {
+                                    if !keep_going {
+                                        aborted.store(true, Ordering::SeqCst);
+                                    }
+                                    Outcome::Failed {
+                                        error,
+                                        duration: instant.elapsed(),
+                                    }
+                                }
+                            }
+                        }
+                    };
+                    (package, outcome, shim_dir)
+                })
+                .collect()
+        })
+    };
+    for (package, outcome, _) in &results {
+        report::emit(Event::Patched {
+            crate_: &package.name,
+            outcome,
+        });
+    }
+
+    let mut shim_dirs = HashMap::new();
+    let mut outcomes: Vec<(&Package, Outcome)> = Vec::new();
+    for (package, outcome, shim_dir) in results {
+        if let Some(shim_dir) = shim_dir {
+            shim_dirs.insert(&package.id, shim_dir);
+        }
+        outcomes.push((package, outcome));
+    }
+    outcomes.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+
+    if interrupted.load(Ordering::SeqCst) {
+        return Err(interrupted_error());
+    }
+
+    if keep_going && !report::is_json() {
+        print_summary(&outcomes);
+    }
+
+    if !keep_going {
+        let failed = outcomes
+            .iter()
+            .position(|(_, outcome)| matches!(outcome, Outcome::Failed { .. }));
+        if let Some((_, Outcome::Failed { error, .. })) = failed.map(|i| outcomes.swap_remove(i)) {
+            return Err(error);
+        }
+    }
+
+    // entries for macros which aren't used anywhere in the workspace anymore,
+    // or whose key changed because a second version of the crate appeared or disappeared.
+    // Computed before the shims in use are replaced, since they record the original source.
+    let expected_keys: Vec<PatchKey> = all_macros
+        .iter()
+        .filter_map(|package| {
+            let source = original_source(package, &watt_crate_dir)?;
+            let table = source.patch_table()?;
+            Some(PatchKey::new(table, &patch_key_name(package, &all_macros)))
+        })
+        .collect();
+
+    let staged_dir = |package: &Package| journal.staging_dir().join(shim_dir_name(package));
+    let mut patched_deps: Vec<Shim> = outcomes
+        .iter()
+        .filter_map(|(package, outcome)| match outcome {
+            // the new shims are still in the staging directory
+            Outcome::Patched { .. } => Some((*package, staged_dir(package))),
+            Outcome::Fresh { .. } => Some((*package, shim_dirs[&package.id].clone())),
+            _ => None,
+        })
+        .collect();
+
+    if let Some(check_args) = options.trial() {
+        let trial = Trial::new(&metadata, &all_macros, &check_args);
+        let safe = trial.safe_subset(&patched_deps, interrupted)?;
+
+        let rejected: Vec<&Package> = patched_deps
+            .iter()
+            .map(|(package, _)| *package)
+            .filter(|package| !safe.iter().any(|(p, _)| p.id == package.id))
+            .collect();
+        for package in &rejected {
+            log::warn!(
+                "not patching '{}' {}, the project doesn't pass `cargo check` with it",
+                package.name,
+                package.version
+            );
+            report::emit(Event::TrialRejected {
+                crate_: &package.name,
+                version: &package.version.to_string(),
+            });
+        }
+        // shims from earlier runs which turned out to break the project
+        let rejected_keys: Vec<String> = rejected
+            .iter()
+            .map(|package| patch_key_name(package, &all_macros))
+            .collect();
+        if !rejected_keys.is_empty() {
+            crate::unpatch::unpatch_logged(
+                &metadata.workspace_root,
+                &rejected_keys,
+                log::Level::Debug,
+            )?;
+        }
+        for package in &rejected {
+            let shim_dir = &shim_dirs[&package.id];
+            // shims in the store may be used by other projects
+            if shim_dir.starts_with(&watt_crate_dir) && shim_dir.exists() {
+                std::fs::remove_dir_all(shim_dir)?;
+            }
+        }
+        patched_deps = safe;
+    }
+
+    // registered before the new shims are in the store, which `cargo watt gc` would otherwise remove
+    let store_lock = match store {
+        Some(_) => {
+            let lock = store::lock().context(ErrorCode::PatchFailed)?;
+            store::register_project(&metadata.workspace_root)
+                .context("failed to register the project for `cargo watt gc`")?;
+            Some(lock)
+        }
+        None => None,
+    };
+
+    // all builds are done, so the new shims can replace the old ones
+    for (package, shim_dir) in &mut patched_deps {
+        if *shim_dir != staged_dir(package) {
+            continue;
+        }
+        let final_dir = &shim_dirs[&package.id];
+        if store.is_some() {
+            store::insert(shim_dir, final_dir).context(ErrorCode::PatchFailed)?;
+        } else {
+            if final_dir.exists() {
+                std::fs::remove_dir_all(final_dir)?;
+            }
+            std::fs::create_dir_all(&watt_crate_dir)?;
+            crate::utils::move_dir(shim_dir, final_dir).context(ErrorCode::PatchFailed)?;
+        }
+        *shim_dir = final_dir.clone();
+    }
+    bundle::install_runtimes(journal.staging_dir(), &watt_crate_dir)
+        .context(ErrorCode::PatchFailed)?;
+
+    let patches = apply_patches(
+        &metadata.workspace_root,
+        &metadata,
+        &patched_deps,
+        &all_macros,
+    )?;
+    drop(store_lock);
+
+    let manifest = crate::utils_toml::read(&metadata.workspace_root.join("Cargo.toml"))?;
+    let unneeded: Vec<String> = patched_entries(&manifest)
+        .into_iter()
+        .filter(|patch_key| !expected_keys.contains(patch_key))
+        .map(|patch_key| patch_key.key)
+        .collect();
+    if !unneeded.is_empty() {
+        crate::unpatch::unpatch(&metadata.workspace_root, &unneeded)?;
+    }
+    remove_missing_shims(&metadata.workspace_root)?;
+    bundle::remove_unused_runtimes(&watt_crate_dir)?;
+    // nothing is left in there once all shims are in the global store
+    if watt_crate_dir.exists() && std::fs::read_dir(&watt_crate_dir)?.next().is_none() {
+        std::fs::remove_dir(&watt_crate_dir)?;
+    }
+
+    let unused = unused_patches(&metadata.workspace_root, &patches)?;
+    for (patch_key, reason) in &unused {
+        log::warn!("the patch for '{}' is not used: {}", patch_key, reason);
+        report::emit(Event::UnusedPatch {
+            crate_: patch_key,
+            reason,
+        });
+    }
+    if options.strict && !unused.is_empty() {
+        let names: Vec<_> = unused.iter().map(|(name, _)| name.as_str()).collect();
+        let error = anyhow::anyhow!("cargo doesn't use the patches for {}", names.join(", "));
+        return Err(error.context(ErrorCode::UnusedPatch));
+    }
+
+    if interrupted.load(Ordering::SeqCst) {
+        return Err(interrupted_error());
+    }
+    Ok(outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Failed { .. }))
+        .map(|(package, _)| format!("{} {}", package.name, package.version))
+        .collect())
+}
+
+/// Points the project in `root` to the `shims`.
+/// `root` is either the workspace itself or the copy used by `--trial`.
+fn apply_patches(
+    root: &Path,
+    metadata: &Metadata,
+    shims: &[Shim],
+    all_macros: &[&Package],
+) -> Result<Vec<NewPatch>, anyhow::Error> {
+    let original_root = &metadata.workspace_root;
+    let in_root = |path: &Path| match path.strip_prefix(original_root) {
+        Ok(relative) => root.join(relative),
+        Err(_) => path.to_path_buf(),
+    };
+
+    let mut patches = Vec::new();
+    for (package, shim_dir) in shims {
+        let shim = ShimInfo::read(shim_dir)
+            .with_context(|| format!("shim for '{}' has no cargo watt metadata", package.name))
+            .context(ErrorCode::PatchFailed)?;
+        match (&shim.source, shim.source.patch_table()) {
+            (_, Some(table)) => patches.push(NewPatch {
+                key: PatchKey::new(table, &patch_key_name(package, all_macros)),
+                package: package.name.clone(),
+                // shims in the store are referred to by absolute paths
+                path: match shim_dir.strip_prefix(root) {
+                    Ok(relative) => format!("./{}", relative.display()),
+                    Err(_) => shim_dir.display().to_string(),
+                },
+            }),
+            // path dependencies can't be patched, so the members have to depend on the shim directly
+            (Source::Path(original_dir), None) => {
+                let shim_dir = shim_dir.canonicalize()?;
+                for toml_path in member_manifests(metadata) {
+                    rewrite_path_dependencies(
+                        &in_root(toml_path),
+                        &package.name,
+                        &in_root(original_dir),
+                        &shim_dir,
+                    )
+                    .context(ErrorCode::PatchFailed)?;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    add_patches(&root.join("Cargo.toml"), &patches).context(ErrorCode::PatchFailed)?;
+    Ok(patches)
+}
+
+/// Resolves the project again and returns the patched crates cargo doesn't use, together with the reason.
+fn unused_patches(
+    workspace_root: &Path,
+    patches: &[NewPatch],
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let metadata = MetadataCommand::new()
+        .current_dir(workspace_root)
+        .features(CargoOpt::AllFeatures)
+        .exec()?;
+
+    // cargo lists the patches it couldn't use in `[[patch.unused]]`
+    let lockfile = crate::utils_toml::read(&workspace_root.join("Cargo.lock"))
+        .context("failed to read Cargo.lock")?;
+    let unused_in_lockfile: Vec<(&str, &str)> = lockfile["patch"]["unused"]
+        .as_array_of_tables()
+        .map(|unused| {
+            unused
+                .iter()
+                .filter_map(|entry| Some((entry["name"].as_str()?, entry["version"].as_str()?)))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut unused = Vec::new();
+    for patch in patches {
+        let name = patch.package.as_str();
+        let packages: Vec<&Package> = metadata
+            .packages
+            .iter()
+            .filter(|package| package.name == name)
+            .collect();
+        let shim_dir = workspace_root.join(&patch.path);
+        if packages
+            .iter()
+            .any(|package| package.manifest_path.starts_with(&shim_dir))
+        {
+            continue;
+        }
+
+        let shim_version = ShimInfo::read(&shim_dir).map(|shim| shim.version);
+        let reason = if let Some((_, version)) = unused_in_lockfile
+            .iter()
+            .find(|(n, v)| *n == name && shim_version.as_deref().is_none_or(|s| s == *v))
+        {
+            let requirements: Vec<String> = metadata
+                .packages
+                .iter()
+                .flat_map(|package| {
+                    package
+                        .dependencies
+                        .iter()
+                        .filter(|dep| dep.name == name)
+                        .map(move |dep| format!("{} requires {}", package.name, dep.req))
+                })
+                .collect();
+            format!(
+                "version mismatch, the shim has version {} but {}",
+                version,
+                requirements.join(", ")
+            )
+        } else if let Some(package) = packages
+            .iter()
+            .find(|package| !package.source.as_ref().is_some_and(|s| s.is_crates_io()))
+        {
+            let source = package
+                .source
+                .as_ref()
+                .map_or_else(|| "a local path".to_string(), |s| s.to_string());
+            format!("wrong source, the dependency comes from {}", source)
+        } else {
+            "cargo doesn't use it".to_string()
+        };
+        unused.push((patch.key.to_string(), reason));
+    }
+
+    Ok(unused)
+}
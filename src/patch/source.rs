@@ -0,0 +1,130 @@
+use crate::{report::ErrorCode, utils, Input};
+use anyhow::Context;
+use cargo_metadata::Package;
+use std::path::{Path, PathBuf};
+
+/// Where a proc macro comes from, which determines how it is fetched and patched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    CratesIo,
+    /// a git repository at the locked revision
+    Git {
+        url: String,
+        rev: String,
+    },
+    /// a local directory, patched by rewriting the path dependencies pointing to it
+    Path(PathBuf),
+}
+
+impl Source {
+    /// Returns the source of `package`, or `None` for registries other than crates.io.
+    pub fn of(package: &Package) -> Option<Source> {
+        let source = match &package.source {
+            None => return Some(Source::Path(package.manifest_path.parent()?.to_path_buf())),
+            Some(source) => source,
+        };
+        if source.is_crates_io() || source.repr == "sparse+https://index.crates.io/" {
+            return Some(Source::CratesIo);
+        }
+
+        // e.g. `git+https://github.com/user/repo?branch=main#0123abcd`
+        let git = source.repr.strip_prefix("git+")?;
+        let (url, rev) = git.split_once('#')?;
+        let url = url.split('?').next().unwrap();
+        Some(Source::Git {
+            url: url.to_string(),
+            rev: rev.to_string(),
+        })
+    }
+
+    pub fn parse(s: &str) -> Option<Source> {
+        if s == "crates-io" {
+            Some(Source::CratesIo)
+        } else if let Some(git) = s.strip_prefix("git+") {
+            let (url, rev) = git.split_once('#')?;
+            Some(Source::Git {
+                url: url.to_string(),
+                rev: rev.to_string(),
+            })
+        } else {
+            s.strip_prefix("path+")
+                .map(|path| Source::Path(path.into()))
+        }
+    }
+
+    /// The `[patch.<table>]` to put the shim into, `None` for path dependencies which can't be patched.
+    pub fn patch_table(&self) -> Option<&str> {
+        match self {
+            Source::CratesIo => Some("crates-io"),
+            Source::Git { url, .. } => Some(url),
+            Source::Path(_) => None,
+        }
+    }
+
+    /// Copies the source of the crate `name` into a temporary directory.
+    /// For crates.io, `checksum` is the one in `Cargo.lock`, which the crate in the registry cache has to match.
+    /// For git repositories, `checkout` is the directory cargo has already checked out the crate to, if known.
+    pub fn fetch(
+        &self,
+        name: &str,
+        version: &str,
+        checksum: Option<&str>,
+        checkout: Option<&Path>,
+    ) -> Result<utils::Tempdir, anyhow::Error> {
+        match self {
+            Source::CratesIo => Input::locked_crate(
+                name.to_string(),
+                version.to_string(),
+                checksum.map(String::from),
+            )
+            .in_tempdir(),
+            Source::Path(path) => Input::path(path.clone()).in_tempdir(),
+            Source::Git { .. } if checkout.is_some_and(Path::exists) => {
+                Input::path(checkout.unwrap().to_path_buf()).in_tempdir()
+            }
+            Source::Git { url, rev } => {
+                log::info!("git clone '{}' at {} into temporary directory...", url, rev);
+                let repo = utils::Tempdir::new().context("failed to crate temporary directory")?;
+                utils::clone_git_into(&repo, url).context(ErrorCode::FetchFailed)?;
+                utils::git_checkout(&repo, rev).context(ErrorCode::FetchFailed)?;
+
+                let crate_dir = find_crate(&repo, name)
+                    .with_context(|| format!("no crate named '{}' in {}", name, url))
+                    .context(ErrorCode::FetchFailed)?;
+                Input::path(crate_dir).in_tempdir()
+            }
+        }
+    }
+
+    /// A checksum of the source, if the source itself doesn't identify the contents already.
+    pub fn checksum(&self) -> Option<String> {
+        match self {
+            Source::Path(path) => utils::hash_dir(path).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::CratesIo => write!(f, "crates-io"),
+            Source::Git { url, rev } => write!(f, "git+{}#{}", url, rev),
+            Source::Path(path) => write!(f, "path+{}", path.display()),
+        }
+    }
+}
+
+/// Finds the directory of the crate `name` in a repository which may contain several crates.
+fn find_crate(repo: &Path, name: &str) -> Option<PathBuf> {
+    walkdir::WalkDir::new(repo)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git" && entry.file_name() != "target")
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name() == "Cargo.toml")
+        .find(|entry| {
+            crate::utils_toml::read(entry.path())
+                .is_ok_and(|manifest| manifest["package"]["name"].as_str() == Some(name))
+        })
+        .and_then(|entry| Some(entry.path().parent()?.to_path_buf()))
+}
@@ -0,0 +1,139 @@
+//! With `--global-store`, shims are shared between projects in `~/.cache/cargo-watt/shims`,
+//! keyed by crate, version and a hash of the source and options they were built from.
+//! The projects using the store are listed next to it, so that `cargo watt gc` knows which shims are still needed.
+
+use super::ShimInfo;
+use crate::utils;
+use anyhow::Context;
+use cargo_metadata::Package;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+/// `$CARGO_WATT_CACHE_DIR`, or `cargo-watt` in the user's cache directory.
+/// The path is absolute and has its symlinks resolved, since it's compared to the canonical paths in `[patch]` entries.
+pub fn cache_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("CARGO_WATT_CACHE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?
+            .join("cargo-watt"),
+    };
+    resolve(&dir)
+}
+
+/// Canonicalizes the part of `path` which exists already, the rest is appended as it is.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return Some(
+                missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |dir, name| dir.join(name)),
+            );
+        }
+        missing.push(existing.file_name()?);
+        existing = existing.parent()?;
+    }
+}
+
+pub fn shims_dir() -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join("shims"))
+}
+
+pub fn contains(dir: &Path) -> bool {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    shims_dir().is_some_and(|store| dir.starts_with(store))
+}
+
+/// The directory of the shim for `package` built from `shim` in the store.
+pub fn shim_dir(store: &Path, package: &Package, shim: &ShimInfo) -> PathBuf {
+    let mut hasher = utils::Fnv::new();
+    hasher.write(shim.source.to_string().as_bytes());
+    hasher.write(shim.checksum.as_deref().unwrap_or_default().as_bytes());
+    for option in &shim.options {
+        hasher.write(b"\0");
+        hasher.write(option.as_bytes());
+    }
+    store.join(format!(
+        "{}-{}-{}",
+        package.name,
+        package.version,
+        hasher.finish()
+    ))
+}
+
+/// Moves a freshly built shim into the store.
+/// It goes through a temporary name first, so that other projects never see a half-copied shim.
+pub fn insert(staged_dir: &Path, shim_dir: &Path) -> Result<(), anyhow::Error> {
+    let store = shim_dir.parent().unwrap();
+    std::fs::create_dir_all(store)?;
+    let tmp = store.join(format!(".tmp-{}", fastrand::u64(..)));
+    utils::move_dir(staged_dir, &tmp)?;
+    if shim_dir.exists() {
+        std::fs::remove_dir_all(shim_dir)?;
+    }
+    std::fs::rename(&tmp, shim_dir)?;
+    Ok(())
+}
+
+/// Locks the store, so that `cargo watt gc` doesn't remove shims which a project is about to use.
+pub fn lock() -> Result<StoreLock, anyhow::Error> {
+    let dir = cache_dir().context("no cache directory for the global store")?;
+    std::fs::create_dir_all(&dir)?;
+
+    let file = File::create(dir.join(".cargo-watt-lock"))?;
+    if file.try_lock().is_err() {
+        log::info!("waiting for the lock on {}...", dir.display());
+        file.lock()
+            .with_context(|| format!("failed to lock {}", dir.display()))?;
+    }
+    Ok(StoreLock { _file: file })
+}
+
+/// The store is locked until this is dropped.
+pub struct StoreLock {
+    _file: File,
+}
+
+fn projects_file() -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join("projects"))
+}
+
+/// The workspace roots of the projects which use the store.
+pub fn projects() -> Vec<PathBuf> {
+    projects_file()
+        .and_then(|file| std::fs::read_to_string(file).ok())
+        .map(|content| content.lines().map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+pub fn set_projects(projects: &[PathBuf]) -> Result<(), anyhow::Error> {
+    let file = match projects_file() {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    let content: String = projects
+        .iter()
+        .map(|root| format!("{}\n", root.display()))
+        .collect();
+    std::fs::create_dir_all(file.parent().unwrap())?;
+    std::fs::write(file, content)?;
+    Ok(())
+}
+
+/// Adds the project to the list of projects using the store. Only call this while holding the `lock`.
+pub fn register_project(workspace_root: &Path) -> Result<(), anyhow::Error> {
+    let mut projects = projects();
+    if !projects.iter().any(|root| root == workspace_root) {
+        projects.push(workspace_root.to_path_buf());
+        set_projects(&projects)?;
+    }
+    Ok(())
+}
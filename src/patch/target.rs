@@ -0,0 +1,81 @@
+//! The crates built by `cargo watt patch` share the target directories in `target/cargo-watt/wasm`,
+//! so the patched syn, quote and proc-macro2 are compiled once per worker instead of once per crate,
+//! and later runs reuse them as well.
+//! Every worker has a directory of its own, which it locks while building, so builds never wait for each other's cargo lock.
+
+use crate::{utils, wasm::TargetDir};
+use anyhow::Context;
+use cargo_metadata::Metadata;
+use std::{fs::File, path::PathBuf, process::Command};
+
+pub struct WasmTargets {
+    dir: PathBuf,
+    jobs: usize,
+}
+
+impl WasmTargets {
+    /// The target directories for `jobs` workers, keyed by the toolchain since switching it would rebuild everything.
+    pub fn new(metadata: &Metadata, jobs: usize) -> Result<Self, anyhow::Error> {
+        let output = Command::new("rustc")
+            .arg("-vV")
+            .current_dir(std::env::temp_dir())
+            .output()
+            .context("cannot execute rustc")?;
+        let mut hasher = utils::Fnv::new();
+        hasher.write(&output.stdout);
+        // part of the RUSTFLAGS of the build
+        if let Some(cargo_home) = std::env::var_os("CARGO_HOME") {
+            hasher.write(cargo_home.to_string_lossy().as_bytes());
+        }
+
+        let dir = metadata
+            .target_directory
+            .join("cargo-watt/wasm")
+            .join(hasher.finish());
+        Ok(WasmTargets { dir, jobs })
+    }
+
+    /// Locks the target directory of the current worker, waiting if another `cargo watt` is building in it.
+    pub fn lock(&self) -> Result<TargetLock, anyhow::Error> {
+        let slot = rayon::current_thread_index().unwrap_or(0);
+        let path = self.dir.join(slot.to_string());
+        std::fs::create_dir_all(&path)?;
+
+        let file = File::create(path.join(".cargo-watt-lock"))?;
+        if file.try_lock().is_err() {
+            log::info!("waiting for the lock on {}...", path.display());
+            file.lock()
+                .with_context(|| format!("failed to lock {}", path.display()))?;
+        }
+
+        // share the cores between the workers, cargo's jobserver only works within one build
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        let jobs = (cores / self.jobs).max(1);
+        Ok(TargetLock {
+            _file: file,
+            path,
+            jobs,
+        })
+    }
+}
+
+/// A target directory which is locked until this is dropped.
+pub struct TargetLock {
+    _file: File,
+    path: PathBuf,
+    jobs: usize,
+}
+impl TargetLock {
+    pub fn target_dir(&self) -> TargetDir<'_> {
+        TargetDir {
+            path: &self.path,
+            jobs: self.jobs,
+        }
+    }
+}
+
+/// Without `--jobs`, builds run in parallel on machines with enough cores for each of them to use several.
+pub fn default_jobs() -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, usize::from);
+    (cores / 4).max(1)
+}
@@ -0,0 +1,227 @@
+//! `cargo watt patch --trial` applies the patches to a copy of the project first and runs `cargo check` there.
+//! If that fails, the shims responsible are found by bisecting, and only the others are applied to the project.
+
+use super::{
+    apply_patches, interrupted_error, manifest::absolutize_path_dependencies, member_manifests,
+    Shim,
+};
+use crate::{report::ErrorCode, utils};
+use anyhow::Context;
+use cargo_metadata::{Metadata, Package};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub struct Trial<'a> {
+    metadata: &'a Metadata,
+    all_macros: &'a [&'a Package],
+    check_args: &'a [String],
+}
+
+impl<'a> Trial<'a> {
+    pub fn new(
+        metadata: &'a Metadata,
+        all_macros: &'a [&'a Package],
+        check_args: &'a [String],
+    ) -> Self {
+        Trial {
+            metadata,
+            all_macros,
+            check_args,
+        }
+    }
+
+    /// Returns the largest subset of `candidates` found by bisecting with which the project still passes `cargo check`.
+    pub fn safe_subset<'p>(
+        &self,
+        candidates: &[Shim<'p>],
+        interrupted: &AtomicBool,
+    ) -> Result<Vec<Shim<'p>>, anyhow::Error> {
+        let scratch = utils::Tempdir::new().context("failed to create temporary directory")?;
+        let copy = self.copy(&scratch.join("project"), candidates)?;
+        let check = |shims: &[Shim]| -> Result<bool, anyhow::Error> {
+            let passed = self.check(&copy, shims)?;
+            if interrupted.load(Ordering::SeqCst) {
+                return Err(interrupted_error());
+            }
+            Ok(passed)
+        };
+
+        log::info!("checking the project with the new patches...");
+        if check(candidates)? {
+            return Ok(candidates.to_vec());
+        }
+
+        log::info!("the project doesn't pass `cargo check` with the new patches, looking for the culprits...");
+        if !check(&[])? {
+            let error = anyhow::anyhow!(
+                "the project doesn't pass `cargo check {}` even without the new patches",
+                self.check_args.join(" ")
+            );
+            return Err(error.context(ErrorCode::PatchFailed));
+        }
+        narrow_down(&check, Vec::new(), candidates)
+    }
+
+    /// Copies the project into `root` without the patches for any of the `candidates`.
+    fn copy(&self, root: &Path, candidates: &[Shim]) -> Result<ProjectCopy, anyhow::Error> {
+        copy_project(self.metadata, root)?;
+
+        // start from the project without any of the candidates, also the ones patched by earlier runs,
+        // by name, since the keys of earlier runs may differ
+        let mut names: Vec<String> = candidates
+            .iter()
+            .map(|(package, _)| package.name.clone())
+            .collect();
+        names.dedup();
+        unpatch_copy(self.metadata, root, &names)?;
+
+        // patching only changes the manifests, and cargo check the lockfile
+        let original_root = &self.metadata.workspace_root;
+        let mut paths: Vec<PathBuf> = member_manifests(self.metadata)
+            .into_iter()
+            .map(|toml_path| root.join(toml_path.strip_prefix(original_root).unwrap()))
+            .collect();
+        paths.push(root.join("Cargo.toml"));
+        paths.push(root.join("Cargo.lock"));
+        paths.sort();
+        paths.dedup();
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let content = std::fs::read(&path).ok();
+                (path, content)
+            })
+            .collect();
+        Ok(ProjectCopy {
+            root: root.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Resets the `copy`, patches `shims` and runs `cargo check`.
+    fn check(&self, copy: &ProjectCopy, shims: &[Shim]) -> Result<bool, anyhow::Error> {
+        copy.reset()?;
+
+        // the copy points at the shims where they are, with absolute paths
+        apply_patches(&copy.root, self.metadata, shims, self.all_macros)?;
+
+        log::debug!(
+            "cargo check with {}",
+            match shims.len() {
+                0 => "no new patches".to_string(),
+                _ => shims
+                    .iter()
+                    .map(|(package, _)| format!("{} {}", package.name, package.version))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            }
+        );
+        let output = Command::new("cargo")
+            .arg("check")
+            .args(self.check_args)
+            .current_dir(&copy.root)
+            // share the build artifacts between the checks
+            .env(
+                "CARGO_TARGET_DIR",
+                self.metadata.target_directory.join("cargo-watt/trial"),
+            )
+            .output()
+            .context("failed to run cargo check")?;
+        if !output.status.success() {
+            log::debug!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+        Ok(output.status.success())
+    }
+}
+
+/// The copy of the project the checks run in, together with the files the checks change.
+struct ProjectCopy {
+    root: PathBuf,
+    /// the content of the files before the first check, `None` for a lockfile which didn't exist yet
+    files: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+impl ProjectCopy {
+    /// Undoes the patches and lockfile changes of the previous check.
+    fn reset(&self) -> Result<(), anyhow::Error> {
+        for (path, content) in &self.files {
+            match content {
+                Some(content) => std::fs::write(path, content)?,
+                None if path.exists() => std::fs::remove_file(path)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds as many of `candidates` to the `safe` ones as possible.
+fn bisect<'p>(
+    check: &dyn Fn(&[Shim]) -> Result<bool, anyhow::Error>,
+    safe: Vec<Shim<'p>>,
+    candidates: &[Shim<'p>],
+) -> Result<Vec<Shim<'p>>, anyhow::Error> {
+    if candidates.is_empty() {
+        return Ok(safe);
+    }
+
+    let mut with_candidates = safe.clone();
+    with_candidates.extend_from_slice(candidates);
+    if check(&with_candidates)? {
+        return Ok(with_candidates);
+    }
+    narrow_down(check, safe, candidates)
+}
+
+/// Like [`bisect`], for `candidates` which are known to contain at least one culprit.
+fn narrow_down<'p>(
+    check: &dyn Fn(&[Shim]) -> Result<bool, anyhow::Error>,
+    safe: Vec<Shim<'p>>,
+    candidates: &[Shim<'p>],
+) -> Result<Vec<Shim<'p>>, anyhow::Error> {
+    if let [(culprit, _)] = candidates {
+        log::info!("'{}' {} breaks the project", culprit.name, culprit.version);
+        return Ok(safe);
+    }
+
+    let (first, second) = candidates.split_at(candidates.len() / 2);
+    let safe = bisect(check, safe, first)?;
+    bisect(check, safe, second)
+}
+
+/// Copies the project of `metadata` into `root`, with the path dependencies pointing at the original directories.
+pub fn copy_project(metadata: &Metadata, root: &Path) -> Result<(), anyhow::Error> {
+    utils::copy_sources(&metadata.workspace_root, root, &metadata.target_directory)
+        .context("failed to copy the project")?;
+    absolutize(metadata, root)
+}
+
+/// Removes the patches for the proc macros `names`, or all of them if it's empty, from a copy made by [`copy_project`].
+pub fn unpatch_copy(
+    metadata: &Metadata,
+    root: &Path,
+    names: &[String],
+) -> Result<(), anyhow::Error> {
+    crate::unpatch::unpatch_logged(root, names, log::Level::Debug)?;
+    // unpatching restores the original relative paths
+    absolutize(metadata, root)
+}
+
+fn absolutize(metadata: &Metadata, root: &Path) -> Result<(), anyhow::Error> {
+    let original_root = &metadata.workspace_root;
+    let mut manifests: Vec<(PathBuf, &Path)> = member_manifests(metadata)
+        .into_iter()
+        .map(|toml_path| {
+            let relative = toml_path.strip_prefix(original_root).unwrap();
+            (root.join(relative), toml_path.parent().unwrap())
+        })
+        .collect();
+    manifests.push((root.join("Cargo.toml"), original_root));
+    for (toml_path, original_dir) in &manifests {
+        absolutize_path_dependencies(toml_path, original_dir, original_root)?;
+    }
+    Ok(())
+}
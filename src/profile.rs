@@ -0,0 +1,207 @@
+//! `cargo watt profile-report` sums up the expansion times logged by shims with `$CARGO_WATT_PROFILE` set.
+//! Every line of the log is `<export>\t<input size>\t<nanoseconds>`, appended by `__watt_record` (see `build::shim_librs`).
+
+use crate::{
+    build,
+    utils::{human_duration, human_size},
+    wasm::{modifications, size::ReportFormat},
+};
+use anyhow::Context;
+use std::{collections::HashMap, path::Path, time::Duration};
+
+struct MacroProfile {
+    /// `<crate>::<macro>`, or the export if it isn't one of cargo watt
+    name: String,
+    calls: usize,
+    total: Duration,
+    p95: Duration,
+    max: Duration,
+    input_size: usize,
+}
+
+/// Sums up the log in `log_file`, or else the one the shims write to (see `build::shim_output`).
+pub fn profile_report(log_file: Option<&Path>, format: ReportFormat) -> Result<(), anyhow::Error> {
+    let log_file = match log_file {
+        Some(file) => file.to_path_buf(),
+        None => build::shim_output("CARGO_WATT_PROFILE", "profile.log")?,
+    };
+    let log = std::fs::read_to_string(&log_file).with_context(|| {
+        format!(
+            "cannot read '{}', build with CARGO_WATT_PROFILE=1 first",
+            log_file.display()
+        )
+    })?;
+
+    let (profiles, malformed) = profiles(&log);
+    if malformed > 0 {
+        // a build which was killed can leave a partial line behind
+        log::warn!("skipped {} malformed lines", malformed);
+    }
+
+    match format {
+        ReportFormat::Table => print_table(&profiles),
+        ReportFormat::Json => {
+            let json: Vec<_> = profiles
+                .iter()
+                .map(|profile| {
+                    serde_json::json!({
+                        "macro": profile.name,
+                        "calls": profile.calls,
+                        "total": profile.total.as_secs_f64(),
+                        "p95": profile.p95.as_secs_f64(),
+                        "max": profile.max.as_secs_f64(),
+                        "input_size": profile.input_size,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::Value::Array(json));
+        }
+    }
+    Ok(())
+}
+
+/// The profiles of the macros in `log`, the slowest first, and the number of malformed lines.
+fn profiles(log: &str) -> (Vec<MacroProfile>, usize) {
+    let mut expansions: HashMap<&str, Vec<(usize, Duration)>> = HashMap::new();
+    let mut malformed = 0;
+    for line in log.lines() {
+        let mut fields = line.split('\t');
+        let parsed = (|| {
+            let export = fields.next()?;
+            let size = fields.next()?.parse().ok()?;
+            let nanos = fields.next()?.parse().ok()?;
+            // lines of processes writing at the same time can't mix, but one cut off can be continued by another
+            if fields.next().is_some() {
+                return None;
+            }
+            Some((export, size, Duration::from_nanos(nanos)))
+        })();
+        match parsed {
+            Some((export, size, duration)) => {
+                expansions.entry(export).or_default().push((size, duration))
+            }
+            None => malformed += 1,
+        }
+    }
+
+    let mut profiles: Vec<MacroProfile> = expansions
+        .into_iter()
+        .map(|(export, calls)| {
+            let mut durations: Vec<Duration> =
+                calls.iter().map(|(_, duration)| *duration).collect();
+            durations.sort();
+            let p95 = (durations.len() * 95).div_ceil(100).max(1) - 1;
+            MacroProfile {
+                name: match modifications::demangle(export) {
+                    Some((namespace, macro_name)) => format!("{}::{}", namespace, macro_name),
+                    None => export.to_string(),
+                },
+                calls: calls.len(),
+                total: durations.iter().sum(),
+                p95: durations[p95],
+                max: durations[durations.len() - 1],
+                input_size: calls.iter().map(|(size, _)| size).sum(),
+            }
+        })
+        .collect();
+    profiles.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+    (profiles, malformed)
+}
+
+fn print_table(profiles: &[MacroProfile]) {
+    println!(
+        "{:<40} {:>7} {:>10} {:>10} {:>10} {:>10}",
+        "macro", "calls", "total", "p95", "max", "input"
+    );
+    for profile in profiles {
+        println!(
+            "{:<40} {:>7} {:>10} {:>10} {:>10} {:>10}",
+            profile.name,
+            profile.calls,
+            human_duration(profile.total),
+            human_duration(profile.p95),
+            human_duration(profile.max),
+            human_size(profile.input_size as u64)
+        );
+    }
+
+    let total: Duration = profiles.iter().map(|profile| profile.total).sum();
+    let calls: usize = profiles.iter().map(|profile| profile.calls).sum();
+    println!();
+    println!(
+        "{} expansions in {}, the first one of each compiler process includes loading the wasm module",
+        calls,
+        human_duration(total)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(lines: &[(&str, usize, u64)]) -> String {
+        lines
+            .iter()
+            .map(|(export, size, millis)| format!("{}\t{}\t{}\n", export, size, millis * 1_000_000))
+            .collect()
+    }
+
+    #[test]
+    fn sum_up_per_macro() {
+        let export = modifications::export_name("my_macros", "derive_debug");
+        let log = log(&[(&export, 10, 3), ("other", 5, 10), (&export, 20, 5)]);
+        let (profiles, malformed) = profiles(&log);
+        assert_eq!(malformed, 0);
+
+        let summary: Vec<_> = profiles
+            .iter()
+            .map(|p| {
+                (
+                    p.name.as_str(),
+                    p.calls,
+                    p.total.as_millis(),
+                    p.max.as_millis(),
+                    p.input_size,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("other", 1, 10, 10, 5),
+                ("my_macros::derive_debug", 2, 8, 5, 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_malformed_lines() {
+        let log = "a\t1\t1000\n\na\t1\na\tx\t1000\na\t1\t1000b\t2\t3000\na\t-1\t1000\na\t1\t1000";
+        let (profiles, malformed) = profiles(log);
+        assert_eq!(malformed, 5);
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0].calls, 2);
+    }
+
+    #[test]
+    fn empty_log() {
+        let (profiles, malformed) = profiles("");
+        assert!(profiles.is_empty());
+        assert_eq!(malformed, 0);
+    }
+
+    fn p95(calls: u64) -> u128 {
+        let lines: Vec<_> = (1..=calls).rev().map(|millis| ("a", 0, millis)).collect();
+        profiles(&log(&lines)).0[0].p95.as_millis()
+    }
+
+    #[test]
+    fn p95_edge_cases() {
+        assert_eq!(p95(1), 1);
+        assert_eq!(p95(2), 2);
+        assert_eq!(p95(19), 19);
+        assert_eq!(p95(20), 19);
+        assert_eq!(p95(21), 20);
+        assert_eq!(p95(100), 95);
+    }
+}
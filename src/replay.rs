@@ -0,0 +1,148 @@
+//! `cargo watt replay` runs the invocations recorded by shims (with `$CARGO_WATT_RECORD` set) against another wasm file
+//! and reports the outputs which changed.
+//! As in `cargo watt expand`, the macros run in a generated proc macro (see `host`).
+
+use crate::{
+    host,
+    report::ErrorCode,
+    utils,
+    wasm::{self, module::Module},
+};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+struct Recording {
+    dir: PathBuf,
+    export: String,
+    kind: String,
+    args: String,
+    input: String,
+    output: String,
+}
+
+impl Recording {
+    fn read(dir: &Path) -> Result<Self, anyhow::Error> {
+        let read = |file: &str| {
+            std::fs::read_to_string(dir.join(file))
+                .with_context(|| format!("cannot read '{}'", dir.join(file).display()))
+        };
+        let export = dir
+            .parent()
+            .and_then(Path::file_name)
+            .context("recordings are stored in <export>/<hash>")?;
+        Ok(Recording {
+            dir: dir.to_path_buf(),
+            export: export.to_string_lossy().into_owned(),
+            kind: read("kind")?,
+            args: read("args")?,
+            input: read("input")?,
+            output: read("output")?,
+        })
+    }
+}
+
+/// Replays the recordings in `recordings_dir` against `wasm_file`.
+pub fn replay(recordings_dir: &Path, wasm_file: &Path) -> Result<(), anyhow::Error> {
+    let module = Module::parse(&wasm::read_wasm_file(wasm_file)?)?;
+
+    let mut recordings = Vec::new();
+    let mut skipped = 0;
+    for entry in walkdir::WalkDir::new(recordings_dir) {
+        let entry = entry?;
+        if entry.file_name() != "kind" {
+            continue;
+        }
+        let recording = Recording::read(entry.path().parent().unwrap())?;
+        if module
+            .exports
+            .iter()
+            .any(|(name, _)| *name == recording.export)
+        {
+            recordings.push(recording);
+        } else {
+            skipped += 1;
+        }
+    }
+    if recordings.is_empty() {
+        anyhow::bail!(
+            "found no recordings of the macros in '{}' in '{}'",
+            wasm_file.display(),
+            recordings_dir.display()
+        );
+    }
+    log::info!("replaying {} invocations...", recordings.len());
+
+    let host = utils::Tempdir::new().context("failed to create temporary directory")?;
+    let compress = wasm_file.extension().is_some_and(|e| e == "deflate");
+    let wasm = std::fs::read(wasm_file)?;
+    host::write(
+        &host,
+        &wasm,
+        compress,
+        None,
+        host::EXPAND,
+        invocations(&recordings)?,
+    )?;
+    let results = host::run(&host, "replay", true, |_| {})?;
+
+    let mut changed = 0;
+    for (recording, result) in recordings.iter().zip(results.as_array().unwrap()) {
+        let replayed = match (result["tokens"].as_str(), result["panic"].as_str()) {
+            (Some(tokens), _) => tokens.to_string(),
+            (None, message) => format!("panicked: {}", message.unwrap_or_default()),
+        };
+        if replayed == recording.output {
+            continue;
+        }
+        changed += 1;
+
+        println!(
+            "{} ({}): the output changed",
+            recording.dir.display(),
+            recording.kind
+        );
+        if !recording.args.is_empty() {
+            println!("  args:     {}", recording.args);
+        }
+        println!("  input:    {}", recording.input);
+        println!("  recorded: {}", recording.output);
+        println!("  replayed: {}", replayed);
+        println!();
+    }
+
+    println!(
+        "{} replayed, {} unchanged, {} changed, {} skipped (not exported by the wasm file)",
+        recordings.len(),
+        recordings.len() - changed,
+        changed,
+        skipped
+    );
+    if changed > 0 {
+        let error = anyhow::anyhow!("{} of {} outputs changed", changed, recordings.len());
+        return Err(error.context(ErrorCode::OutputsChanged));
+    }
+    Ok(())
+}
+
+/// The invocation of the host on the `recordings`.
+fn invocations(recordings: &[Recording]) -> Result<proc_macro2::TokenStream, anyhow::Error> {
+    let mut invocations = Vec::new();
+    for recording in recordings {
+        let tokens = |source: &str| {
+            source.parse::<proc_macro2::TokenStream>().map_err(|e| {
+                anyhow::anyhow!("cannot tokenize '{}': {:?}", recording.dir.display(), e)
+            })
+        };
+        let args = tokens(&recording.args)?;
+        let input = tokens(&recording.input)?;
+        invocations.push(host::invocation(
+            &recording.export,
+            &recording.kind,
+            args,
+            input,
+        ));
+    }
+    Ok(quote::quote! {
+        host_macro::expand! { #(#invocations)* }
+    })
+}
@@ -0,0 +1,415 @@
+use crate::patch::Outcome;
+use serde_json::json;
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+impl FromStr for MessageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            _ => anyhow::bail!("unknown message format '{}', expected 'human' or 'json'", s),
+        }
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_message_format(format: MessageFormat) {
+    JSON.store(format == MessageFormat::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Categories of errors with stable codes for `--message-format json`.
+/// Attach them using `.context(ErrorCode::..)`, the `Display` impl doubles as the human readable message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorCode {
+    FetchFailed,
+    InvalidManifest,
+    UnsupportedCrate,
+    ModificationFailed,
+    CompileFailed,
+    DisallowedImport,
+    WasmToolFailed,
+    VerificationFailed,
+    PatchFailed,
+    UnusedPatch,
+    Interrupted,
+    ExpansionFailed,
+    DifferencesFound,
+    OutputsChanged,
+}
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::FetchFailed => "fetch-failed",
+            ErrorCode::InvalidManifest => "invalid-manifest",
+            ErrorCode::UnsupportedCrate => "unsupported-crate",
+            ErrorCode::ModificationFailed => "modification-failed",
+            ErrorCode::CompileFailed => "compile-failed",
+            ErrorCode::DisallowedImport => "disallowed-import",
+            ErrorCode::WasmToolFailed => "wasm-tool-failed",
+            ErrorCode::VerificationFailed => "verification-failed",
+            ErrorCode::PatchFailed => "patch-failed",
+            ErrorCode::UnusedPatch => "unused-patch",
+            ErrorCode::Interrupted => "interrupted",
+            ErrorCode::ExpansionFailed => "expansion-failed",
+            ErrorCode::DifferencesFound => "differences-found",
+            ErrorCode::OutputsChanged => "outputs-changed",
+        }
+    }
+
+    pub fn of(error: &anyhow::Error) -> Option<ErrorCode> {
+        error.downcast_ref::<ErrorCode>().copied()
+    }
+}
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ErrorCode::FetchFailed => "failed to fetch crate",
+            ErrorCode::InvalidManifest => "invalid Cargo.toml",
+            ErrorCode::UnsupportedCrate => "crate is not supported by cargo watt",
+            ErrorCode::ModificationFailed => "failed to make modifications to crate",
+            ErrorCode::CompileFailed => "failed to compile crate to wasm",
+            ErrorCode::DisallowedImport => "wasm module has disallowed imports",
+            ErrorCode::WasmToolFailed => "failed to optimize wasm module",
+            ErrorCode::VerificationFailed => "verification failed",
+            ErrorCode::PatchFailed => "failed to patch project",
+            ErrorCode::UnusedPatch => "cargo doesn't use some of the patches",
+            ErrorCode::Interrupted => "interrupted, the changes have been rolled back",
+            ErrorCode::ExpansionFailed => "failed to expand the macro",
+            ErrorCode::DifferencesFound => {
+                "the wasm module and the native crate expand differently"
+            }
+            ErrorCode::OutputsChanged => "the replayed outputs differ from the recorded ones",
+        };
+        f.write_str(message)
+    }
+}
+
+pub enum Event<'a> {
+    SourceFetched {
+        source: &'a str,
+        directory: &'a Path,
+    },
+    ModificationsApplied {
+        macros: Vec<String>,
+    },
+    CompileStarted {
+        crate_: &'a str,
+    },
+    CompileFinished {
+        crate_: &'a str,
+        success: bool,
+        duration: Duration,
+    },
+    /// size of the wasm file after a stage, e.g. `cargo build`, `wasm-strip` or `wasm-opt`
+    WasmSize {
+        crate_: &'a str,
+        stage: &'a str,
+        size: u64,
+    },
+    /// the `--size-report` of a crate, taken before wasm-strip
+    SizeReport {
+        crate_: &'a str,
+        report: serde_json::Value,
+    },
+    CrateGenerated {
+        crate_: &'a str,
+        path: &'a Path,
+    },
+    Verified {
+        file: &'a Path,
+        success: bool,
+    },
+    Patched {
+        crate_: &'a str,
+        outcome: &'a Outcome,
+    },
+    /// a patch which cargo ignores, e.g. because of a version mismatch
+    UnusedPatch {
+        crate_: &'a str,
+        reason: &'a str,
+    },
+    /// a shim which `cargo watt patch --trial` left out because the project doesn't pass `cargo check` with it
+    TrialRejected {
+        crate_: &'a str,
+        version: &'a str,
+    },
+    Error {
+        error: &'a anyhow::Error,
+    },
+}
+
+impl Event<'_> {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Event::SourceFetched { source, directory } => {
+                json!({ "event": "source-fetched", "source": source, "directory": directory })
+            }
+            Event::ModificationsApplied { macros } => {
+                json!({ "event": "modifications-applied", "macros": macros })
+            }
+            Event::CompileStarted { crate_ } => {
+                json!({ "event": "compile-started", "crate": crate_ })
+            }
+            Event::CompileFinished {
+                crate_,
+                success,
+                duration,
+            } => json!({
+                "event": "compile-finished",
+                "crate": crate_,
+                "success": success,
+                "duration": duration.as_secs_f64(),
+            }),
+            Event::WasmSize {
+                crate_,
+                stage,
+                size,
+            } => json!({ "event": "wasm-size", "crate": crate_, "stage": stage, "size": size }),
+            Event::SizeReport { crate_, report } => {
+                json!({ "event": "size-report", "crate": crate_, "report": report })
+            }
+            Event::CrateGenerated { crate_, path } => {
+                json!({ "event": "crate-generated", "crate": crate_, "path": path })
+            }
+            Event::Verified { file, success } => {
+                json!({ "event": "verified", "file": file, "success": success })
+            }
+            Event::Patched { crate_, outcome } => {
+                // `success` predates `status`, which tells apart the ways a crate can succeed
+                let success = !matches!(outcome, Outcome::Failed { .. });
+                let mut value = json!({ "event": "patched", "crate": crate_, "success": success });
+                match outcome {
+                    Outcome::Patched { size, duration } => {
+                        value["status"] = json!("patched");
+                        value["size"] = json!(size);
+                        value["duration"] = json!(duration.as_secs_f64());
+                    }
+                    Outcome::Fresh { size } => {
+                        value["status"] = json!("fresh");
+                        value["size"] = json!(size);
+                    }
+                    Outcome::Failed { error, duration } => {
+                        value["status"] = json!("failed");
+                        value["error"] = error_json(error);
+                        value["duration"] = json!(duration.as_secs_f64());
+                    }
+                    Outcome::Skipped { reason } => {
+                        value["status"] = json!("skipped");
+                        value["reason"] = json!(reason);
+                    }
+                }
+                value
+            }
+            Event::UnusedPatch { crate_, reason } => {
+                json!({ "event": "unused-patch", "crate": crate_, "reason": reason })
+            }
+            Event::TrialRejected { crate_, version } => {
+                json!({ "event": "trial-rejected", "crate": crate_, "version": version })
+            }
+            Event::Error { error } => {
+                let mut value = error_json(error);
+                value["event"] = json!("error");
+                value
+            }
+        }
+    }
+}
+
+fn error_json(error: &anyhow::Error) -> serde_json::Value {
+    json!({
+        "code": ErrorCode::of(error).map_or("unknown", ErrorCode::as_str),
+        "message": format!("{:#}", error),
+    })
+}
+
+/// Prints the event as a line of JSON to stdout when running with `--message-format json`.
+pub fn emit(event: Event) {
+    if is_json() {
+        println!("{}", event.to_json());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn build_events() {
+        let directory = Path::new("/tmp/serde_derive");
+        assert_eq!(
+            Event::SourceFetched {
+                source: "crates-io+serde_derive",
+                directory,
+            }
+            .to_json(),
+            json!({ "event": "source-fetched", "source": "crates-io+serde_derive", "directory": "/tmp/serde_derive" })
+        );
+        assert_eq!(
+            Event::ModificationsApplied {
+                macros: vec!["derive_serialize".to_string()],
+            }
+            .to_json(),
+            json!({ "event": "modifications-applied", "macros": ["derive_serialize"] })
+        );
+        assert_eq!(
+            Event::CompileStarted {
+                crate_: "serde_derive"
+            }
+            .to_json(),
+            json!({ "event": "compile-started", "crate": "serde_derive" })
+        );
+        assert_eq!(
+            Event::CompileFinished {
+                crate_: "serde_derive",
+                success: true,
+                duration: Duration::from_millis(1500),
+            }
+            .to_json(),
+            json!({ "event": "compile-finished", "crate": "serde_derive", "success": true, "duration": 1.5 })
+        );
+        assert_eq!(
+            Event::WasmSize {
+                crate_: "serde_derive",
+                stage: "wasm-strip",
+                size: 1024,
+            }
+            .to_json(),
+            json!({ "event": "wasm-size", "crate": "serde_derive", "stage": "wasm-strip", "size": 1024 })
+        );
+        assert_eq!(
+            Event::SizeReport {
+                crate_: "serde_derive",
+                report: json!({ "total": 1024 }),
+            }
+            .to_json(),
+            json!({ "event": "size-report", "crate": "serde_derive", "report": { "total": 1024 } })
+        );
+        assert_eq!(
+            Event::CrateGenerated {
+                crate_: "serde_derive",
+                path: Path::new("serde_derive-watt"),
+            }
+            .to_json(),
+            json!({ "event": "crate-generated", "crate": "serde_derive", "path": "serde_derive-watt" })
+        );
+        assert_eq!(
+            Event::Verified {
+                file: Path::new("serde_derive.wasm"),
+                success: false,
+            }
+            .to_json(),
+            json!({ "event": "verified", "file": "serde_derive.wasm", "success": false })
+        );
+    }
+
+    #[test]
+    fn patch_events() {
+        let patched = Outcome::Patched {
+            size: 2048,
+            duration: Duration::from_secs(2),
+        };
+        assert_eq!(
+            Event::Patched {
+                crate_: "thiserror-impl",
+                outcome: &patched,
+            }
+            .to_json(),
+            json!({ "event": "patched", "crate": "thiserror-impl", "success": true, "status": "patched", "size": 2048, "duration": 2.0 })
+        );
+        let fresh = Outcome::Fresh { size: 2048 };
+        assert_eq!(
+            Event::Patched {
+                crate_: "thiserror-impl",
+                outcome: &fresh,
+            }
+            .to_json(),
+            json!({ "event": "patched", "crate": "thiserror-impl", "success": true, "status": "fresh", "size": 2048 })
+        );
+        let failed = Outcome::Failed {
+            error: anyhow::anyhow!("error[E0432]").context(ErrorCode::CompileFailed),
+            duration: Duration::from_secs(1),
+        };
+        assert_eq!(
+            Event::Patched {
+                crate_: "thiserror-impl",
+                outcome: &failed,
+            }
+            .to_json(),
+            json!({
+                "event": "patched",
+                "crate": "thiserror-impl",
+                "success": false,
+                "status": "failed",
+                "error": { "code": "compile-failed", "message": "failed to compile crate to wasm: error[E0432]" },
+                "duration": 1.0,
+            })
+        );
+        let skipped = Outcome::Skipped {
+            reason: "workspace member",
+        };
+        assert_eq!(
+            Event::Patched {
+                crate_: "thiserror-impl",
+                outcome: &skipped,
+            }
+            .to_json(),
+            json!({ "event": "patched", "crate": "thiserror-impl", "success": true, "status": "skipped", "reason": "workspace member" })
+        );
+        assert_eq!(
+            Event::UnusedPatch {
+                crate_: "thiserror-impl",
+                reason: "cargo doesn't use it",
+            }
+            .to_json(),
+            json!({ "event": "unused-patch", "crate": "thiserror-impl", "reason": "cargo doesn't use it" })
+        );
+        assert_eq!(
+            Event::TrialRejected {
+                crate_: "thiserror-impl",
+                version: "1.0.69",
+            }
+            .to_json(),
+            json!({ "event": "trial-rejected", "crate": "thiserror-impl", "version": "1.0.69" })
+        );
+    }
+
+    #[test]
+    fn error_events() {
+        let error = Err::<(), _>(anyhow::anyhow!("no such crate"))
+            .context(ErrorCode::FetchFailed)
+            .context("failed to build serde_derive")
+            .unwrap_err();
+        assert_eq!(
+            Event::Error { error: &error }.to_json(),
+            json!({
+                "event": "error",
+                "code": "fetch-failed",
+                "message": "failed to build serde_derive: failed to fetch crate: no such crate",
+            })
+        );
+
+        let error = anyhow::anyhow!("something else");
+        assert_eq!(
+            Event::Error { error: &error }.to_json(),
+            json!({ "event": "error", "code": "unknown", "message": "something else" })
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{
+    patch::{self, WATT_DIR},
+    report::ErrorCode,
+    utils::human_size,
+    PackageSelection,
+};
+use anyhow::Context;
+use cargo_metadata::{CargoOpt, Metadata, MetadataCommand, Package};
+use std::path::{Path, PathBuf};
+
+/// A `[patch]` entry added by `cargo watt patch`
+struct PatchEntry {
+    key: String,
+    package: String,
+    dir: PathBuf,
+}
+impl PatchEntry {
+    fn shim_version(&self) -> Option<String> {
+        let manifest = crate::utils_toml::read(&self.dir.join("Cargo.toml")).ok()?;
+        manifest["package"]["version"].as_str().map(String::from)
+    }
+
+    fn wasm_size(&self) -> Option<u64> {
+        patch::wasm_size(&self.dir)
+    }
+}
+
+fn patch_entries(metadata: &Metadata) -> Result<Vec<PatchEntry>, anyhow::Error> {
+    let workspace_root = &metadata.workspace_root;
+    let manifest = crate::utils_toml::read(&workspace_root.join("Cargo.toml"))
+        .context(ErrorCode::InvalidManifest)?;
+
+    let mut entries: Vec<PatchEntry> = patch::patched_entries(&manifest)
+        .into_iter()
+        .filter_map(|patch_key| {
+            let path = patch_key.entry(&manifest)["path"].as_str()?;
+            let dir = workspace_root.join(path);
+            Some(PatchEntry {
+                package: patch_key.package(&manifest).to_string(),
+                dir: dir.canonicalize().unwrap_or(dir),
+                key: patch_key.to_string(),
+            })
+        })
+        .collect();
+
+    // path dependencies of the members which point to a shim instead
+    for member in &metadata.workspace_members {
+        let toml_path = &metadata[member].manifest_path;
+        let member_dir = toml_path.parent().unwrap();
+        let member_manifest =
+            crate::utils_toml::read(toml_path).context(ErrorCode::InvalidManifest)?;
+        for rewrite in patch::rewritten_dependencies(&member_manifest) {
+            let path =
+                member_manifest[rewrite.kind.as_str()][rewrite.key.as_str()]["path"].as_str();
+            if let Some(path) = path {
+                let dir = member_dir.join(path);
+                entries.push(PatchEntry {
+                    key: format!("{} ({})", rewrite.key, metadata[member].name),
+                    package: rewrite.package,
+                    dir: dir.canonicalize().unwrap_or(dir),
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// The shim directories the project at `path` points to.
+pub fn shim_dirs(path: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let entries = patch_entries(&patch::workspace(path)?)?;
+    Ok(entries.into_iter().map(|entry| entry.dir).collect())
+}
+
+enum State<'a> {
+    /// cargo uses the shim
+    Patched(&'a PatchEntry),
+    /// there is a shim, but cargo doesn't use it, usually because its version doesn't match the lockfile
+    Ignored(&'a PatchEntry),
+    Unpatched,
+}
+
+fn state<'a>(package: &Package, entries: &'a [PatchEntry]) -> State<'a> {
+    let package_dir = package
+        .manifest_path
+        .parent()
+        .and_then(|dir| dir.canonicalize().ok());
+    if let Some(entry) = entries
+        .iter()
+        .find(|entry| Some(&entry.dir) == package_dir.as_ref())
+    {
+        return State::Patched(entry);
+    }
+    // with several versions of the crate, only a shim of a compatible version is meant for this one
+    let compatible = |entry: &&PatchEntry| match entry.shim_version() {
+        Some(version) => cargo_metadata::Version::parse(&version).is_ok_and(|version| {
+            patch::compatible_version(&version) == patch::compatible_version(&package.version)
+        }),
+        None => true,
+    };
+    match entries
+        .iter()
+        .filter(|entry| entry.package == package.name)
+        .find(compatible)
+    {
+        Some(entry) => State::Ignored(entry),
+        None => State::Unpatched,
+    }
+}
+
+fn proc_macros<'a>(
+    metadata: &'a Metadata,
+    members: &[&'a cargo_metadata::PackageId],
+    no_dev: bool,
+) -> Result<Vec<&'a Package>, anyhow::Error> {
+    let mut macros: Vec<_> = patch::reachable_packages(metadata, members, no_dev)?
+        .into_iter()
+        .filter(|package| patch::is_proc_macro(package) && !members.contains(&&package.id))
+        .collect();
+    macros.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+    Ok(macros)
+}
+
+fn print_table(macros: &[&Package], entries: &[PatchEntry]) {
+    println!(
+        "{:<30} {:<10} {:<10} {:<10} {:<5} {:>10}",
+        "crate", "locked", "status", "shim", "used", "wasm size"
+    );
+    for package in macros {
+        let (status, entry) = match state(package, entries) {
+            State::Patched(entry) => ("patched", Some(entry)),
+            State::Ignored(entry) => match entry.shim_version() {
+                Some(version) if version != package.version.to_string() => ("stale", Some(entry)),
+                _ => ("ignored", Some(entry)),
+            },
+            State::Unpatched => ("unpatched", None),
+        };
+        let shim_version = entry.and_then(PatchEntry::shim_version);
+        let used = match entry {
+            Some(_) if status == "patched" => "yes",
+            Some(_) => "no",
+            None => "",
+        };
+        let size = entry
+            .and_then(PatchEntry::wasm_size)
+            .map(human_size)
+            .unwrap_or_default();
+        println!(
+            "{:<30} {:<10} {:<10} {:<10} {:<5} {:>10}",
+            package.name,
+            package.version,
+            status,
+            shim_version.unwrap_or_default(),
+            used,
+            size
+        );
+    }
+}
+
+fn print_tree(
+    metadata: &Metadata,
+    selection: &PackageSelection,
+    entries: &[PatchEntry],
+) -> Result<(), anyhow::Error> {
+    for member in patch::selected_members(metadata, selection)? {
+        println!("{}", metadata[member].name);
+
+        let macros = proc_macros(metadata, &[member], selection.no_dev)?;
+        let without_dev = proc_macros(metadata, &[member], true)?;
+        for (i, package) in macros.iter().enumerate() {
+            let branch = if i + 1 == macros.len() {
+                "└──"
+            } else {
+                "├──"
+            };
+            let status = match state(package, entries) {
+                State::Patched(_) => "patched",
+                State::Ignored(_) => "not used",
+                State::Unpatched => "unpatched",
+            };
+            let dev = if without_dev.iter().any(|p| p.id == package.id) {
+                ""
+            } else {
+                ", dev-dependency"
+            };
+            println!(
+                "{} {} {} ({}{})",
+                branch, package.name, package.version, status, dev
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Lists the proc macros of the project and whether they are patched.
+pub fn status(path: &Path, selection: &PackageSelection, tree: bool) -> Result<(), anyhow::Error> {
+    let metadata = MetadataCommand::new()
+        .current_dir(path)
+        .features(CargoOpt::AllFeatures)
+        .exec()?;
+    let entries = patch_entries(&metadata)?;
+
+    if tree {
+        return print_tree(&metadata, selection, &entries);
+    }
+
+    let members = patch::selected_members(&metadata, selection)?;
+    let macros = proc_macros(&metadata, &members, selection.no_dev)?;
+    print_table(&macros, &entries);
+
+    let all_macros = proc_macros(
+        &metadata,
+        &metadata.workspace_members.iter().collect::<Vec<_>>(),
+        false,
+    )?;
+    let unused: Vec<_> = entries
+        .iter()
+        .filter(|entry| {
+            !all_macros
+                .iter()
+                .any(|package| package.name == entry.package)
+        })
+        .collect();
+    if !unused.is_empty() {
+        println!();
+        println!("patch entries without a matching dependency:");
+        let root = metadata.workspace_root.canonicalize()?;
+        for entry in unused {
+            let dir = entry.dir.strip_prefix(&root).unwrap_or(&entry.dir);
+            println!("  {} ({})", entry.key, dir.display());
+        }
+    }
+
+    if !metadata.workspace_root.join(WATT_DIR).exists() && entries.is_empty() {
+        log::info!("the project hasn't been patched yet, use `cargo watt patch`");
+    }
+
+    Ok(())
+}
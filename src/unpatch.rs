@@ -0,0 +1,290 @@
+use crate::{
+    patch::{self, PatchKey, WATT_DIR},
+    report::ErrorCode,
+};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Deletes the shim directory `dir`, as long as it is inside of `.watt-patched`.
+fn remove_patched_dir(workspace_root: &Path, dir: &Path) -> Result<(), anyhow::Error> {
+    // shims in the global store are left to `cargo watt gc`
+    if patch::store::contains(dir) {
+        return Ok(());
+    }
+    if !dir.starts_with(workspace_root.join(WATT_DIR)) {
+        log::warn!("not deleting '{}' outside of {}", dir.display(), WATT_DIR);
+        return Ok(());
+    }
+
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)
+            .with_context(|| format!("failed to delete '{}'", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// The `[patch]` entries removed by [`remove_patches`].
+struct Removal<'a> {
+    removed: Vec<PatchKey>,
+    /// the names in `crates` which matched one of the entries
+    found: Vec<&'a String>,
+    /// the shims of the removed entries which no remaining entry points to
+    dirs: Vec<PathBuf>,
+}
+
+/// Removes the `[patch]` entries added by cargo watt for `crates`, or all of them if empty.
+/// Shim directories are resolved relative to `root`.
+fn remove_patches<'a>(
+    manifest: &mut toml_edit::Document,
+    root: &Path,
+    crates: &'a [String],
+) -> Removal<'a> {
+    // whether the patch entry is for one of `crates`, either by its key or, unless that's the key of another entry,
+    // by the name of the crate, which removes the entries for all of its versions
+    let entries = patch::patched_entries(manifest);
+    let matches = |patch_key: &PatchKey, manifest: &toml_edit::Document, name: &String| {
+        *name == patch_key.key
+            || (name == patch_key.package(manifest) && !entries.iter().any(|e| e.key == *name))
+    };
+
+    let mut removed = Vec::new();
+    let mut remaining = Vec::new();
+    for patch_key in entries.iter().cloned() {
+        if patch_key.entry(manifest).is_none() {
+            log::warn!("patch entry for '{}' has already been removed", patch_key);
+            continue;
+        }
+        if crates.is_empty()
+            || crates
+                .iter()
+                .any(|name| matches(&patch_key, manifest, name))
+        {
+            removed.push(patch_key);
+        } else {
+            remaining.push(patch_key);
+        }
+    }
+    let found = crates
+        .iter()
+        .filter(|name| removed.iter().any(|key| matches(key, manifest, name)))
+        .collect();
+
+    // a shim can be shared by an entry which has been renamed, e.g. when a second version of the crate disappeared
+    let kept_dirs: Vec<_> = remaining
+        .iter()
+        .filter_map(|patch_key| patch_key.entry(manifest)["path"].as_str())
+        .map(|dir| root.join(dir))
+        .collect();
+    let mut dirs = Vec::new();
+    for patch_key in &removed {
+        let entry = crate::utils_toml::remove(
+            manifest.as_table_mut(),
+            &["patch", &patch_key.table, &patch_key.key],
+        );
+        if let Some(dir) = entry.as_ref().and_then(|entry| entry["path"].as_str()) {
+            let dir = root.join(dir);
+            if !kept_dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    patch::set_patched_entries(manifest, &remaining);
+
+    Removal {
+        removed,
+        found,
+        dirs,
+    }
+}
+
+/// Removes the patches added by `cargo watt patch`, or only those for `crates` if given.
+/// That's the `[patch]` entries in the root manifest and the rewritten path dependencies of the members.
+pub fn unpatch(path: &Path, crates: &[String]) -> Result<(), anyhow::Error> {
+    unpatch_logged(path, crates, log::Level::Info)
+}
+
+/// Like [`unpatch`], but reports what it does at `level`, e.g. to stay quiet on the copies used by `--trial`.
+pub fn unpatch_logged(
+    path: &Path,
+    crates: &[String],
+    level: log::Level,
+) -> Result<(), anyhow::Error> {
+    let workspace = patch::workspace(path)?;
+    let path = &workspace.workspace_root;
+    let toml_path = path.join("Cargo.toml");
+    let mut manifest = crate::utils_toml::read(&toml_path).context(ErrorCode::InvalidManifest)?;
+
+    let Removal {
+        removed,
+        mut found,
+        dirs,
+    } = remove_patches(&mut manifest, path, crates);
+    for dir in dirs {
+        remove_patched_dir(path, &dir)?;
+    }
+    for patch_key in &removed {
+        log::log!(level, "removed patch for '{}'", patch_key);
+    }
+
+    std::fs::write(&toml_path, manifest.to_string_in_original_order())
+        .context(ErrorCode::PatchFailed)?;
+
+    let mut restored = 0;
+    for member in workspace.workspace_members.iter() {
+        let member_toml = &workspace[member].manifest_path;
+        let rewrites = patch::rewritten_dependencies(
+            &crate::utils_toml::read(member_toml).context(ErrorCode::InvalidManifest)?,
+        );
+        found.extend(crates.iter().filter(|name| {
+            rewrites
+                .iter()
+                .any(|r| r.key == **name || r.package == **name)
+        }));
+
+        let shim_dirs = patch::restore_path_dependencies(member_toml, crates, level)
+            .context(ErrorCode::PatchFailed)?;
+        restored += shim_dirs.len();
+        for dir in shim_dirs {
+            if let Ok(dir) = dir.canonicalize() {
+                remove_patched_dir(&path.canonicalize()?, &dir)?;
+            }
+        }
+    }
+
+    if removed.is_empty() && restored == 0 && crates.is_empty() {
+        log::log!(level, "nothing to unpatch");
+    }
+    // callers which want it quiet don't want this warning either
+    let warning = match level {
+        log::Level::Info => log::Level::Warn,
+        level => level,
+    };
+    for name in crates {
+        if !found.contains(&name) {
+            log::log!(warning, "'{}' is not patched by cargo watt", name);
+        }
+    }
+
+    let watt_dir = path.join(WATT_DIR);
+    patch::remove_unused_runtimes(&watt_dir)?;
+    let is_empty = std::fs::read_dir(&watt_dir).is_ok_and(|mut dir| dir.next().is_none());
+    if is_empty {
+        std::fs::remove_dir(&watt_dir)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_VERSIONS: &str = r#"[package]
+name = "consumer"
+
+[patch.crates-io]
+my-macros-0-1 = { package = "my-macros", path = "./.watt-patched/my-macros-0.1.0" }
+my-macros-1 = { package = "my-macros", path = "./.watt-patched/my-macros-1.0.0" }
+serde = { path = "../serde" }
+
+[package.metadata.watt]
+patched = ["my-macros-0-1", "my-macros-1"]
+"#;
+
+    fn unpatch(
+        toml: &str,
+        crates: &[&str],
+    ) -> (
+        toml_edit::Document,
+        Vec<PatchKey>,
+        Vec<String>,
+        Vec<PathBuf>,
+    ) {
+        let mut manifest: toml_edit::Document = toml.parse().unwrap();
+        let crates: Vec<String> = crates.iter().map(|name| name.to_string()).collect();
+        let removal = remove_patches(&mut manifest, Path::new("/project"), &crates);
+        let found = removal.found.into_iter().cloned().collect();
+        (manifest, removal.removed, found, removal.dirs)
+    }
+
+    #[test]
+    fn unpatch_version_by_key() {
+        let (manifest, removed, found, dirs) = unpatch(TWO_VERSIONS, &["my-macros-1"]);
+        assert_eq!(removed, [PatchKey::new("crates-io", "my-macros-1")]);
+        assert_eq!(found, ["my-macros-1"]);
+        assert_eq!(dirs, [Path::new("/project/.watt-patched/my-macros-1.0.0")]);
+        assert_eq!(
+            patch::patched_entries(&manifest),
+            [PatchKey::new("crates-io", "my-macros-0-1")]
+        );
+        assert!(manifest["patch"]["crates-io"]["my-macros-1"].is_none());
+    }
+
+    #[test]
+    fn unpatch_all_versions_by_name() {
+        let (manifest, removed, found, dirs) = unpatch(TWO_VERSIONS, &["my-macros"]);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(found, ["my-macros"]);
+        assert_eq!(dirs.len(), 2);
+        assert!(patch::patched_entries(&manifest).is_empty());
+        assert!(manifest["package"]["metadata"].is_none());
+        // patches which weren't added by cargo watt stay
+        assert_eq!(
+            manifest["patch"]["crates-io"]["serde"]["path"].as_str(),
+            Some("../serde")
+        );
+    }
+
+    #[test]
+    fn unpatch_everything() {
+        let (manifest, removed, found, _) = unpatch(TWO_VERSIONS, &[]);
+        assert_eq!(removed.len(), 2);
+        assert!(found.is_empty());
+        assert!(manifest["patch"]["crates-io"]["serde"].is_value());
+    }
+
+    #[test]
+    fn name_which_is_a_key_only_matches_that_entry() {
+        let toml = r#"[package]
+name = "consumer"
+
+[patch.crates-io]
+my-macros = { path = "./.watt-patched/my-macros-1.0.0" }
+my-macros-0-1 = { package = "my-macros", path = "./.watt-patched/my-macros-0.1.0" }
+
+[package.metadata.watt]
+patched = ["my-macros", "my-macros-0-1"]
+"#;
+        let (manifest, removed, found, _) = unpatch(toml, &["my-macros"]);
+        assert_eq!(removed, [PatchKey::new("crates-io", "my-macros")]);
+        assert_eq!(found, ["my-macros"]);
+        assert_eq!(
+            patch::patched_entries(&manifest),
+            [PatchKey::new("crates-io", "my-macros-0-1")]
+        );
+    }
+
+    #[test]
+    fn keep_shim_shared_with_renamed_entry() {
+        let toml = r#"[package]
+name = "consumer"
+
+[patch.crates-io]
+my-macros = { path = "./.watt-patched/my-macros-1.0.0" }
+my-macros-1 = { package = "my-macros", path = "./.watt-patched/my-macros-1.0.0" }
+
+[package.metadata.watt]
+patched = ["my-macros", "my-macros-1"]
+"#;
+        let (_, removed, _, dirs) = unpatch(toml, &["my-macros-1"]);
+        assert_eq!(removed, [PatchKey::new("crates-io", "my-macros-1")]);
+        assert!(dirs.is_empty());
+    }
+
+    #[test]
+    fn unpatch_unknown_crate() {
+        let (manifest, removed, found, dirs) = unpatch(TWO_VERSIONS, &["other-macros"]);
+        assert!(removed.is_empty() && found.is_empty() && dirs.is_empty());
+        assert_eq!(patch::patched_entries(&manifest).len(), 2);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::report::ErrorCode;
 use anyhow::Context;
 use std::{
     path::{Path, PathBuf},
@@ -8,18 +9,31 @@ use walkdir::WalkDir;
 const UNSUPPORTED_DEPS: &[&str] = &["syn-mid", "synstructure"];
 
 pub fn parse_validate_toml(path: &Path) -> Result<toml_edit::Document, anyhow::Error> {
-    let input = std::fs::read_to_string(path).context("error reading Cargo.toml")?;
-    let manifest: toml_edit::Document = input.parse().context("failed to parse Cargo.toml")?;
+    let manifest = parse_toml(path).context(ErrorCode::InvalidManifest)?;
 
     if manifest["package"]["edition"].as_str() != Some("2018") {
         log::warn!("macro crate is not 2018 edition, which may not work in some cases");
     }
 
+    validate_toml(&manifest).context(ErrorCode::UnsupportedCrate)?;
+
+    Ok(manifest)
+}
+
+fn parse_toml(path: &Path) -> Result<toml_edit::Document, anyhow::Error> {
+    let input = std::fs::read_to_string(path).context("error reading Cargo.toml")?;
+    let manifest: toml_edit::Document = input.parse().context("failed to parse Cargo.toml")?;
+
     anyhow::ensure!(!manifest["package"].is_none(), "Cargo.toml has no package");
     anyhow::ensure!(
         manifest["package"]["name"].as_str().is_some(),
         "Cargo.toml has no name"
     );
+
+    Ok(manifest)
+}
+
+fn validate_toml(manifest: &toml_edit::Document) -> Result<(), anyhow::Error> {
     anyhow::ensure!(
         manifest["lib"]["proc-macro"].as_bool().unwrap_or(false),
         "crate is not a proc macro"
@@ -38,7 +52,26 @@ pub fn parse_validate_toml(path: &Path) -> Result<toml_edit::Document, anyhow::E
         );
     }
 
-    Ok(manifest)
+    Ok(())
+}
+
+pub fn human_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{}b", bytes),
+        1024..=1_048_575 => format!("{:.1}kb", bytes as f64 / 1024.0),
+        _ => format!("{:.2}mb", bytes as f64 / 1024.0 / 1024.0),
+    }
+}
+
+pub fn human_duration(duration: std::time::Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs >= 1.0 {
+        format!("{:.2}s", secs)
+    } else if secs >= 0.001 {
+        format!("{:.1}ms", secs * 1e3)
+    } else {
+        format!("{:.1}µs", secs * 1e6)
+    }
 }
 
 pub fn copy_all(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
@@ -70,6 +103,49 @@ pub fn copy_all(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Copies a crate or workspace, leaving out the target directory and version control.
+pub fn copy_sources(from: &Path, to: &Path, target_directory: &Path) -> Result<(), anyhow::Error> {
+    let entries = WalkDir::new(from)
+        .into_iter()
+        .filter_entry(|entry| entry.path() != target_directory && entry.file_name() != ".git");
+    for entry in entries {
+        let entry = entry?;
+        let new_path = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(new_path)?;
+        } else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), new_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Mirrors `from` into `to` using hard links, falling back to copies e.g. across file systems.
+pub fn link_all(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let new_file = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(new_file)?;
+        } else if entry.file_type().is_file()
+            && std::fs::hard_link(entry.path(), &new_file).is_err()
+        {
+            std::fs::copy(entry.path(), new_file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the directory `from` to `to`, which must not exist yet.
+pub fn move_dir(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_all(from, to)?;
+    std::fs::remove_dir_all(from)?;
+    Ok(())
+}
+
 pub fn clone_git_into(path: &Path, url: &str) -> Result<(), anyhow::Error> {
     let output = Command::new("git")
         .arg("clone")
@@ -84,6 +160,70 @@ pub fn clone_git_into(path: &Path, url: &str) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+pub fn git_checkout(path: &Path, rev: &str) -> Result<(), anyhow::Error> {
+    let output = Command::new("git")
+        .args(["checkout", "--quiet", rev])
+        .current_dir(path)
+        .output()
+        .context("cannot execute git")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("failed to checkout {}: {}", rev, stderr.trim());
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a, which is stable across platforms and rust versions unlike `DefaultHasher`.
+pub struct Fnv(u64);
+impl Fnv {
+    pub fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// Hashes the paths and contents of all files in `dir`, skipping `target` and hidden directories.
+pub fn hash_dir(dir: &Path) -> Result<String, anyhow::Error> {
+    let mut hasher = Fnv::new();
+    let files = WalkDir::new(dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            entry.depth() == 0 || !(name.starts_with('.') || name == "target")
+        });
+    for file in files {
+        let file = file?;
+        if file.file_type().is_file() {
+            let path = file.path().strip_prefix(dir)?;
+            hasher.write(path.to_string_lossy().as_bytes());
+            hasher.write(&std::fs::read(file.path())?);
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// Returns the path of `to` relative to the directory `from`, both have to be absolute.
+pub fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut path: PathBuf = std::iter::repeat_n("..", from.len() - common).collect();
+    path.extend(to[common..].iter());
+    path
+}
+
 pub fn cargo(path: &Path, args: &[&str]) -> Result<(), anyhow::Error> {
     let output = Command::new("cargo")
         .args(args)
@@ -102,7 +242,40 @@ pub fn cargo_fmt(path: &Path) -> Result<(), anyhow::Error> {
 }
 
 #[cfg(feature = "crates")]
-pub fn download_crate(path: &Path, crate_: &str) -> Result<(), anyhow::Error> {
+/// With the `checksum` of the version from `Cargo.lock`, the `.crate` file cargo has downloaded already is used if it matches.
+pub fn download_crate(
+    path: &Path,
+    crate_: &str,
+    version: Option<&str>,
+    checksum: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    if let (Some(version), Some(checksum)) = (version, checksum) {
+        if let Some(cached) = cached_crate(crate_, version, checksum) {
+            log::debug!("using cached crate file {}", cached.display());
+            let file = std::fs::File::open(&cached)?;
+            return unpack_crate(path, file);
+        }
+    }
+
+    let dl_path = match version {
+        Some(version) => format!("/api/v1/crates/{}/{}/download", crate_, version),
+        None => latest_dl_path(crate_)?,
+    };
+
+    let crate_response = ureq::get(&format!("https://crates.io{}", dl_path)).call();
+
+    anyhow::ensure!(
+        !crate_response.error(),
+        "crates io request failed with status code {}: {}",
+        crate_response.status(),
+        crate_response.status_text()
+    );
+
+    unpack_crate(path, crate_response.into_reader())
+}
+
+#[cfg(feature = "crates")]
+fn latest_dl_path(crate_: &str) -> Result<String, anyhow::Error> {
     let err = |e| move || anyhow::anyhow!("invalid crates.io response: {}", e);
 
     let response = ureq::get(&format!("https://crates.io/api/v1/crates/{}", crate_)).call();
@@ -120,22 +293,60 @@ pub fn download_crate(path: &Path, crate_: &str) -> Result<(), anyhow::Error> {
         .ok_or_else(err("no versions"))?;
     let dl_path = versions
         .iter()
-        .filter(|v| !v["yanked"].as_bool().unwrap_or(false))
-        .next()
+        .find(|v| !v["yanked"].as_bool().unwrap_or(false))
         .ok_or_else(|| anyhow::anyhow!("no published non-yanked versions"))?["dl_path"]
         .as_str()
         .ok_or_else(err("missing dl_path"))?;
 
-    let crate_response = ureq::get(&format!("https://crates.io{}", dl_path)).call();
+    Ok(dl_path.to_string())
+}
 
-    anyhow::ensure!(
-        !crate_response.error(),
-        "crates io request failed with status code {}: {}",
-        crate_response.status(),
-        crate_response.status_text()
-    );
+#[cfg(feature = "crates")]
+pub fn cargo_home() -> Option<PathBuf> {
+    std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")))
+}
+
+#[cfg(feature = "crates")]
+/// Looks for the `.crate` file cargo has already downloaded into `$CARGO_HOME/registry/cache`.
+/// The cache has a directory per registry and might contain broken downloads, so the file has to match the sha256 `checksum`.
+fn cached_crate(crate_: &str, version: &str, checksum: &str) -> Option<PathBuf> {
+    let cache = cargo_home()?.join("registry").join("cache");
+    let file_name = format!("{}-{}.crate", crate_, version);
+    std::fs::read_dir(cache)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|registry| registry.path().join(&file_name))
+        .filter(|file| file.is_file())
+        .find(|file| match sha256(file) {
+            Ok(sha256) if sha256 == checksum => true,
+            Ok(_) => {
+                log::warn!(
+                    "the checksum of {} doesn't match Cargo.lock, downloading the crate instead",
+                    file.display()
+                );
+                false
+            }
+            Err(_) => false,
+        })
+}
 
-    let tar = flate2::read::GzDecoder::new(crate_response.into_reader());
+#[cfg(feature = "crates")]
+fn sha256(path: &Path) -> Result<String, std::io::Error> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(feature = "crates")]
+fn unpack_crate(path: &Path, reader: impl std::io::Read) -> Result<(), anyhow::Error> {
+    let tar = flate2::read::GzDecoder::new(reader);
     let mut archive = tar::Archive::new(tar);
 
     for entry in archive.entries()? {
@@ -145,7 +356,7 @@ pub fn download_crate(path: &Path, crate_: &str) -> Result<(), anyhow::Error> {
         let new_path = path.join(path_without_parent);
 
         if let Some(parent) = new_path.parent() {
-            std::fs::create_dir_all(&parent)?;
+            std::fs::create_dir_all(parent)?;
         }
 
         entry.unpack(new_path)?;
@@ -192,3 +403,28 @@ impl std::ops::Deref for Tempdir {
         &self.path
     }
 }
+
+#[cfg(all(test, feature = "crates"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_crate_matching_checksum() {
+        let cargo_home = Tempdir::new().unwrap();
+        let cache = cargo_home.join("registry").join("cache");
+        for (registry, contents) in [("a-broken", "broken"), ("b-index", "")] {
+            std::fs::create_dir_all(cache.join(registry)).unwrap();
+            std::fs::write(cache.join(registry).join("foo-1.0.0.crate"), contents).unwrap();
+        }
+        std::env::set_var("CARGO_HOME", &*cargo_home);
+
+        // the sha256 of the empty file
+        let checksum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            cached_crate("foo", "1.0.0", checksum),
+            Some(cache.join("b-index").join("foo-1.0.0.crate"))
+        );
+        assert_eq!(cached_crate("foo", "1.0.0", &"0".repeat(64)), None);
+        assert_eq!(cached_crate("foo", "1.0.1", checksum), None);
+    }
+}
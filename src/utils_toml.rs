@@ -1,22 +1,36 @@
-use toml_edit::{Document, InlineTable, Item, Table};
+use anyhow::Context;
+use std::path::Path;
+use toml_edit::{Document, InlineTable, Item, Table, Value};
 
 // returns the (possibly just generated) [patch.crates.io] section
 pub fn implicit_table<'a>(manifest: &'a mut Document, a: &str, b: &str) -> &'a mut Table {
-    let mut a_table = Table::new();
-    a_table.set_implicit(true);
+    implicit_tables(manifest.as_table_mut(), &[a, b])
+}
 
-    let patch = manifest[a]
-        .or_insert(Item::Table(a_table))
-        .as_table_mut()
-        .unwrap();
+// returns the table at `keys`, inserting implicit tables for the ones which don't exist yet
+pub fn implicit_tables<'a>(table: &'a mut Table, keys: &[&str]) -> &'a mut Table {
+    keys.iter().fold(table, |table, k| {
+        let mut new_table = Table::new();
+        new_table.set_implicit(true);
 
-    let mut b_table = Table::new();
-    b_table.set_implicit(true);
+        table[&key(k)]
+            .or_insert(Item::Table(new_table))
+            .as_table_mut()
+            .unwrap()
+    })
+}
 
-    patch[b]
-        .or_insert(Item::Table(b_table))
-        .as_table_mut()
-        .unwrap()
+/// Quotes `key` unless it is a bare key. Indexing with `[]` parses the key, so that's needed for keys like urls.
+pub fn key(key: &str) -> String {
+    let is_bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if is_bare {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
 }
 
 pub fn dependency(kind: &str, dep: &str) -> InlineTable {
@@ -24,3 +38,36 @@ pub fn dependency(kind: &str, dep: &str) -> InlineTable {
     table.get_or_insert(kind, dep);
     table
 }
+
+/// Removes the item at `keys` and afterwards every table on the way there which ended up empty.
+pub fn remove(table: &mut Table, keys: &[&str]) -> Option<Item> {
+    let (first, rest) = keys.split_first()?;
+    if rest.is_empty() {
+        return table.remove(first);
+    }
+    if !table.contains_table(first) {
+        return None;
+    }
+
+    let child = table[&key(first)].as_table_mut()?;
+    let removed = remove(child, rest);
+    if child.is_empty() {
+        table.remove(first);
+    }
+    removed
+}
+
+/// Sets the `path` of a dependency, keeping the whitespace around the old one,
+/// so that restoring it gives back the manifest as it was.
+pub fn set_path(dependency: &mut Item, path: &str) {
+    let value = match dependency["path"].as_value() {
+        Some(Value::String(old)) => toml_edit::decorated(path.into(), old.prefix(), old.suffix()),
+        _ => path.into(),
+    };
+    dependency["path"] = Item::Value(value);
+}
+
+pub fn read(path: &Path) -> Result<Document, anyhow::Error> {
+    let input = std::fs::read_to_string(path).context("error reading Cargo.toml")?;
+    input.parse().context("failed to parse Cargo.toml")
+}
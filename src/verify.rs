@@ -1,4 +1,7 @@
-use crate::{utils, wasm, CompilationOptions};
+use crate::{
+    report::{self, ErrorCode, Event},
+    utils, wasm, CompilationOptions,
+};
 use std::path::Path;
 
 pub fn verify(
@@ -6,25 +9,34 @@ pub fn verify(
     compilation_options: &CompilationOptions,
     wasm_file: &Path,
 ) -> Result<(), anyhow::Error> {
-    let is_wasm = wasm_file.extension().map_or(false, |e| e == "wasm");
+    let is_wasm = wasm_file.extension().is_some_and(|e| e == "wasm");
     anyhow::ensure!(is_wasm, "'{}' is not a wasm file", wasm_file.display());
 
     let wasm = std::fs::read(wasm_file)?;
 
     let manifest = utils::parse_validate_toml(&directory.join("Cargo.toml"))?;
     let name = manifest["package"]["name"].as_str().unwrap();
-    let (_, compiled_wasm) = wasm::compile(directory, &manifest, compilation_options, true)?;
+    // with `--message-format json`, the output of cargo would end up between the events on stdout
+    let verbose = !report::is_json();
+    let (_, compiled_wasm) =
+        wasm::compile(directory, &manifest, compilation_options, None, verbose)?;
 
-    if wasm != compiled_wasm {
+    let success = wasm == compiled_wasm;
+    report::emit(Event::Verified {
+        file: wasm_file,
+        success,
+    });
+
+    if !success {
         let file_name = wasm_file.file_name().unwrap().to_str().unwrap();
-        anyhow::bail!(
+        let error = anyhow::anyhow!(
             "'{}' wasn't compiled from '{}' or the build wasn't reproducible",
             &file_name,
             name
         );
+        return Err(error.context(ErrorCode::VerificationFailed));
     }
 
-    eprintln!(" Success!");
-
+    log::info!("'{}' was compiled from '{}'", wasm_file.display(), name);
     Ok(())
 }
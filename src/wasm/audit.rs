@@ -0,0 +1,155 @@
+use super::module::{self, Import, Module};
+use std::{collections::BTreeSet, path::Path};
+
+/// The import module of the functions provided by watt's proc-macro2 runtime.
+pub const WATT_IMPORT_MODULE: &str = "watt-0.4";
+const WATT_IMPORTS: &[&str] = &[
+    "token_stream_serialize",
+    "token_stream_deserialize",
+    "token_stream_parse",
+    "literal_to_string",
+    "bytes_len",
+    "bytes_read",
+    "string_new",
+    "string_len",
+    "string_read",
+    "print_panic",
+];
+
+pub struct AuditedImport<'a> {
+    pub import: &'a Import,
+    pub allowed: bool,
+    /// crates containing functions which call the import, empty if the name section is missing
+    pub crates: BTreeSet<&'a str>,
+}
+
+fn is_allowed(import: &Import, extra_allowed: &[String]) -> bool {
+    let watt_import = import.module == WATT_IMPORT_MODULE && WATT_IMPORTS.contains(&&*import.name);
+    watt_import
+        || extra_allowed
+            .iter()
+            .any(|allowed| *allowed == format!("{}::{}", import.module, import.name))
+}
+
+/// Checks every import of the module against the set of functions the watt runtime provides.
+/// `extra_allowed` contains additional imports in the form `module::name`.
+pub fn audit<'a>(module: &'a Module, extra_allowed: &[String]) -> Vec<AuditedImport<'a>> {
+    module
+        .imports
+        .iter()
+        .map(|import| {
+            let crates = import
+                .function
+                .into_iter()
+                .flat_map(|function| module.callers(function))
+                .filter_map(|caller| caller.name.as_deref())
+                .filter_map(module::crate_of)
+                .collect();
+            AuditedImport {
+                import,
+                allowed: is_allowed(import, extra_allowed),
+                crates,
+            }
+        })
+        .collect()
+}
+
+/// Fails if the module imports anything the watt runtime doesn't provide.
+pub fn check(module: &Module, extra_allowed: &[String]) -> Result<(), anyhow::Error> {
+    let disallowed: Vec<_> = audit(module, extra_allowed)
+        .into_iter()
+        .filter(|audited| !audited.allowed)
+        .collect();
+
+    for audited in &disallowed {
+        log::error!(
+            "disallowed import '{}::{}'{}",
+            audited.import.module,
+            audited.import.name,
+            introduced_by(audited)
+        );
+    }
+    anyhow::ensure!(
+        disallowed.is_empty(),
+        "the wasm module imports {} function(s) the watt runtime doesn't provide. Use --allow-import <module::name> to allow them anyway.",
+        disallowed.len()
+    );
+
+    Ok(())
+}
+
+fn introduced_by(audited: &AuditedImport) -> String {
+    if audited.crates.is_empty() {
+        return String::new();
+    }
+    let crates: Vec<&str> = audited.crates.iter().copied().collect();
+    format!(" (introduced by {})", crates.join(", "))
+}
+
+pub fn audit_imports(wasm_file: &Path, extra_allowed: &[String]) -> Result<(), anyhow::Error> {
+    let wasm = super::read_wasm_file(wasm_file)?;
+    let module = Module::parse(&wasm)?;
+
+    if !module.has_names() {
+        log::warn!("the wasm file has no name section, so imports can't be attributed to crates");
+    }
+
+    let audited = audit(&module, extra_allowed);
+    for audited in &audited {
+        println!(
+            "{:<10} {}::{}{}",
+            if audited.allowed { "ok" } else { "DISALLOWED" },
+            audited.import.module,
+            audited.import.name,
+            introduced_by(audited)
+        );
+    }
+
+    let n_disallowed = audited.iter().filter(|audited| !audited.allowed).count();
+    anyhow::ensure!(
+        n_disallowed == 0,
+        "{} of {} imports aren't provided by the watt runtime",
+        n_disallowed,
+        audited.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(module: &str, name: &str) -> Import {
+        Import {
+            module: module.to_string(),
+            name: name.to_string(),
+            function: None,
+        }
+    }
+
+    #[test]
+    fn watt_runtime_imports() {
+        assert!(is_allowed(&import("watt-0.4", "token_stream_parse"), &[]));
+        assert!(is_allowed(&import("watt-0.4", "print_panic"), &[]));
+        // the runtime only provides a fixed set of functions, in its own module
+        assert!(!is_allowed(&import("watt-0.4", "fd_write"), &[]));
+        assert!(!is_allowed(&import("watt-0.3", "token_stream_parse"), &[]));
+        assert!(!is_allowed(&import("env", "token_stream_parse"), &[]));
+    }
+
+    #[test]
+    fn extra_allowed_imports() {
+        let allowed = ["wasi_snapshot_preview1::fd_write".to_string()];
+        assert!(is_allowed(
+            &import("wasi_snapshot_preview1", "fd_write"),
+            &allowed
+        ));
+        assert!(is_allowed(&import("watt-0.4", "string_new"), &allowed));
+        assert!(!is_allowed(
+            &import("wasi_snapshot_preview1", "fd_read"),
+            &allowed
+        ));
+        assert!(!is_allowed(&import("env", "fd_write"), &allowed));
+    }
+}
@@ -0,0 +1,213 @@
+//! The proc macros of a wasm module are embedded in a custom section of it,
+//! so the shim crate can be regenerated from the wasm file alone (`cargo watt shim`).
+
+use super::{modifications, ProcMacroFn, ProcMacroKind};
+use anyhow::Context;
+use serde_json::{json, Value};
+use wasmparser::Payload;
+
+/// The name of the custom section.
+pub const SECTION: &str = "cargo-watt-macros";
+
+/// The crate a wasm module was compiled from and the proc macros it exports.
+pub struct MacroMetadata {
+    pub crate_: String,
+    pub version: String,
+    pub fns: Vec<ProcMacroFn>,
+}
+
+impl MacroMetadata {
+    fn to_json(&self) -> Value {
+        let fns: Vec<Value> = self
+            .fns
+            .iter()
+            .map(|f| {
+                let attrs: Vec<String> = f
+                    .attrs
+                    .iter()
+                    .map(|attr| quote::quote!(#attr).to_string())
+                    .collect();
+                json!({
+                    "name": f.name.to_string(),
+                    "export": f.export,
+                    "kind": kind_name(f.kind),
+                    "attrs": attrs,
+                })
+            })
+            .collect();
+        json!({ "crate": self.crate_, "version": self.version, "macros": fns })
+    }
+
+    fn from_json(value: &Value) -> Result<Self, anyhow::Error> {
+        let string = |value: &Value, key: &str| {
+            value[key]
+                .as_str()
+                .map(String::from)
+                .with_context(|| format!("missing '{}'", key))
+        };
+
+        let mut fns = Vec::new();
+        for f in value["macros"].as_array().context("missing 'macros'")? {
+            let name = string(f, "name")?;
+            let attrs: Vec<&str> = f["attrs"]
+                .as_array()
+                .context("missing 'attrs'")?
+                .iter()
+                .filter_map(Value::as_str)
+                .collect();
+            let attrs = attrs
+                .join(" ")
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid attributes of '{}': {:?}", name, e))?;
+            fns.push(ProcMacroFn {
+                name: syn::parse_str(&name)?,
+                export: string(f, "export")?,
+                attrs: modifications::parse_attributes(attrs)?,
+                kind: parse_kind(&string(f, "kind")?)?,
+            });
+        }
+
+        Ok(MacroMetadata {
+            crate_: string(value, "crate")?,
+            version: string(value, "version")?,
+            fns,
+        })
+    }
+
+    /// Appends the metadata as a custom section to the uncompressed `wasm` module.
+    pub fn embed(&self, wasm: &mut Vec<u8>) {
+        let data = self.to_json().to_string();
+
+        let mut content = Vec::new();
+        write_leb128(&mut content, SECTION.len());
+        content.extend_from_slice(SECTION.as_bytes());
+        content.extend_from_slice(data.as_bytes());
+
+        // custom sections have the id 0 and may appear anywhere in the module
+        wasm.push(0);
+        write_leb128(wasm, content.len());
+        wasm.extend_from_slice(&content);
+    }
+
+    /// Reads the metadata embedded into the uncompressed `wasm` module, if there is any.
+    pub fn read(wasm: &[u8]) -> Result<Option<Self>, anyhow::Error> {
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            if let Payload::CustomSection {
+                name: SECTION,
+                data,
+                ..
+            } = payload.context("failed to parse wasm module")?
+            {
+                let value: Value = serde_json::from_slice(data)?;
+                let metadata = MacroMetadata::from_json(&value)
+                    .with_context(|| format!("invalid '{}' section", SECTION))?;
+                return Ok(Some(metadata));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn kind_name(kind: ProcMacroKind) -> &'static str {
+    match kind {
+        ProcMacroKind::Macro => "macro",
+        ProcMacroKind::Derive => "derive",
+        ProcMacroKind::Attribute => "attribute",
+        ProcMacroKind::ProcMacroHack => "proc-macro-hack",
+    }
+}
+
+fn parse_kind(kind: &str) -> Result<ProcMacroKind, anyhow::Error> {
+    match kind {
+        "macro" => Ok(ProcMacroKind::Macro),
+        "derive" => Ok(ProcMacroKind::Derive),
+        "attribute" => Ok(ProcMacroKind::Attribute),
+        "proc-macro-hack" => Ok(ProcMacroKind::ProcMacroHack),
+        _ => anyhow::bail!("unknown kind of proc macro '{}'", kind),
+    }
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module with just the header, the custom section is appended to it.
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn metadata(macros: usize) -> MacroMetadata {
+        let fns = (0..macros)
+            .map(|i| ProcMacroFn {
+                name: quote::format_ident!("derive_{}", i),
+                export: modifications::export_name("my_macros", &format!("derive_{}", i)),
+                attrs: vec![syn::parse_quote!(#[proc_macro_derive(MyDerive, attributes(my))])],
+                kind: ProcMacroKind::Derive,
+            })
+            .collect();
+        MacroMetadata {
+            crate_: "my-macros".to_string(),
+            version: "1.2.3".to_string(),
+            fns,
+        }
+    }
+
+    fn round_trip(macros: usize) {
+        let mut wasm = EMPTY_MODULE.to_vec();
+        metadata(macros).embed(&mut wasm);
+        let read = MacroMetadata::read(&wasm).unwrap().unwrap();
+
+        assert_eq!(read.crate_, "my-macros");
+        assert_eq!(read.version, "1.2.3");
+        assert_eq!(read.fns.len(), macros);
+        for (i, f) in read.fns.iter().enumerate() {
+            assert_eq!(f.name, format!("derive_{}", i));
+            assert_eq!(f.export, format!("__watt_9my_macros_derive_{}", i));
+            assert_eq!(f.kind, ProcMacroKind::Derive);
+            let attr = &f.attrs[0];
+            assert_eq!(
+                quote::quote!(#attr).to_string(),
+                "# [proc_macro_derive (MyDerive , attributes (my))]"
+            );
+        }
+    }
+
+    #[test]
+    fn embed_and_read() {
+        round_trip(1);
+    }
+
+    #[test]
+    fn embed_and_read_multi_byte_length() {
+        // the section gets longer than 2^14 bytes, which takes three bytes of LEB128
+        round_trip(200);
+    }
+
+    #[test]
+    fn leb128() {
+        let encode = |value| {
+            let mut out = Vec::new();
+            write_leb128(&mut out, value);
+            out
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(127), [0x7f]);
+        assert_eq!(encode(128), [0x80, 0x01]);
+        assert_eq!(encode(624_485), [0xe5, 0x8e, 0x26]);
+    }
+
+    #[test]
+    fn read_without_section() {
+        assert!(MacroMetadata::read(EMPTY_MODULE).unwrap().is_none());
+    }
+}
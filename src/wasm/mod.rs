@@ -1,8 +1,16 @@
-mod modifications;
+pub mod audit;
+pub mod metadata;
+pub mod modifications;
+pub mod module;
+pub mod size;
 
+pub use metadata::MacroMetadata;
 pub use modifications::{ProcMacroFn, ProcMacroKind};
 
-use crate::CompilationOptions;
+use crate::{
+    report::{self, ErrorCode, Event},
+    CompilationOptions,
+};
 use anyhow::Context;
 use std::{
     path::{Path, PathBuf},
@@ -12,7 +20,7 @@ use std::{
 #[cfg(unix)]
 fn file_size(path: &Path) -> Result<u64, std::io::Error> {
     use std::os::unix::fs::MetadataExt;
-    Ok(std::fs::metadata(&path)?.size())
+    Ok(std::fs::metadata(path)?.size())
 }
 #[cfg(not(unix))]
 fn file_size(path: &Path) -> Result<u64, anyhow::Error> {
@@ -20,24 +28,63 @@ fn file_size(path: &Path) -> Result<u64, anyhow::Error> {
     Ok(content.len() as u64)
 }
 
+/// A target directory shared with other builds, instead of `target` in the crate directory.
+pub struct TargetDir<'a> {
+    pub path: &'a Path,
+    /// the number of jobs for `cargo build`
+    pub jobs: usize,
+}
+
 /// Modify Cargo.toml (proc-macro2 patch, cdylib) and lib.rs (see modifications::librs).
 /// Then call cargo build --release --target wasm32-unknown-unknown and read to compiled wasm file.
 pub fn compile(
     directory: &Path,
     manifest: &toml_edit::Document,
     compilation_options: &CompilationOptions,
+    target_dir: Option<&TargetDir>,
     verbose: bool,
 ) -> Result<(Vec<ProcMacroFn>, Vec<u8>), anyhow::Error> {
     let name = manifest["package"]["name"].as_str().unwrap();
+    let version = manifest["package"]["version"].as_str().unwrap_or_default();
+
+    let fns = modifications::make_modifications(directory, &name.replace('-', "_"))
+        .context(ErrorCode::ModificationFailed)?;
+    report::emit(Event::ModificationsApplied {
+        macros: fns.iter().map(|f| f.name.to_string()).collect(),
+    });
+
+    let metadata = MacroMetadata {
+        crate_: name.to_string(),
+        version: version.to_string(),
+        fns,
+    };
+    let wasm = build(
+        directory,
+        name,
+        compilation_options,
+        target_dir,
+        Some(&metadata),
+        verbose,
+    )?;
+    Ok((metadata.fns, wasm))
+}
 
-    let fns = modifications::make_modifications(&directory)
-        .context("failed to make modifications to crate")?;
-
+/// Compiles the modified crate `name` in `directory` to wasm and runs the wasm tools on it.
+/// The `metadata` is embedded afterwards, since wasm-strip removes custom sections.
+pub fn build(
+    directory: &Path,
+    name: &str,
+    compilation_options: &CompilationOptions,
+    target_dir: Option<&TargetDir>,
+    metadata: Option<&MacroMetadata>,
+    verbose: bool,
+) -> Result<Vec<u8>, anyhow::Error> {
     log::info!("begin compiling crate...");
+    report::emit(Event::CompileStarted { crate_: name });
     let instant = std::time::Instant::now();
     let mut command = Command::new("cargo");
     command
-        .args(&[
+        .args([
             "build",
             "--target",
             "wasm32-unknown-unknown",
@@ -45,59 +92,135 @@ pub fn compile(
             "--all-features",
         ])
         .env("RUSTFLAGS", rust_flags())
-        .current_dir(&directory);
+        .current_dir(directory);
+    if let Some(target_dir) = target_dir {
+        command
+            .env("CARGO_TARGET_DIR", target_dir.path)
+            .arg("--jobs")
+            .arg(target_dir.jobs.to_string());
+    }
 
     if !verbose {
         command.stderr(std::process::Stdio::null());
         command.stdout(std::process::Stdio::null());
     }
 
-    let status = command.status().context("failed to run cargo build")?;
+    let status = command
+        .status()
+        .context("failed to run cargo build")
+        .context(ErrorCode::CompileFailed)?;
     log::info!("finished in {:.1}s", instant.elapsed().as_secs_f32());
-    anyhow::ensure!(status.success(), "cargo failed");
-
-    let wasm_path = std::env::var("CARGO_TARGET_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| directory.join("target"))
-        .join("wasm32-unknown-unknown/release")
-        .join(name.replace("-", "_"))
-        .with_extension("wasm");
+    report::emit(Event::CompileFinished {
+        crate_: name,
+        success: status.success(),
+        duration: instant.elapsed(),
+    });
+    if !status.success() {
+        return Err(anyhow::anyhow!("cargo failed").context(ErrorCode::CompileFailed));
+    }
 
-    let size = file_size(&wasm_path)?;
-    log::debug!("wasm file size: {}kb", size / 1024);
+    let file_name = format!("{}.wasm", name.replace("-", "_"));
+    let wasm_path = match target_dir {
+        // cargo hard links the file to its cache, which wasm-strip and wasm-opt must not modify
+        Some(target_dir) => {
+            let built = target_dir
+                .path
+                .join("wasm32-unknown-unknown/release")
+                .join(&file_name);
+            let copy = directory.join(&file_name);
+            std::fs::copy(&built, &copy).context("cannot read compiled wasm")?;
+            copy
+        }
+        None => std::env::var("CARGO_TARGET_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| directory.join("target"))
+            .join("wasm32-unknown-unknown/release")
+            .join(&file_name),
+    };
+
+    report_size(name, "cargo build", file_size(&wasm_path)?);
+
+    // the name section is needed to attribute imports to crates, so this has to happen before wasm-strip
+    let module = std::fs::read(&wasm_path).context("cannot read compiled wasm")?;
+    let module = module::Module::parse(&module)?;
+    audit::check(&module, &compilation_options.allow_import)
+        .context(ErrorCode::DisallowedImport)?;
+    // stdout is reserved for the JSON events, and crates compiled in parallel must not mix their reports
+    if let Some(format) = compilation_options.size_report {
+        let size_report = size::SizeReport::new(&module);
+        match report::is_json() {
+            true => report::emit(Event::SizeReport {
+                crate_: name,
+                report: size_report.to_json(20),
+            }),
+            false => eprint!(
+                "size report of '{}':\n{}",
+                name,
+                size_report.render(format, 20)
+            ),
+        }
+    }
 
     if !compilation_options.no_wasm_strip {
-        let status = Command::new("wasm-strip")
-            .arg(&wasm_path)
-            .status()
-            .context("failed to run wasm-strip")?;
-        anyhow::ensure!(status.success(), "wasm-strip failed");
-
-        let size = file_size(&wasm_path)?;
-        log::debug!("after wasm-strip: {}kb", size / 1024);
+        run_wasm_tool(Command::new("wasm-strip").arg(&wasm_path))?;
+        report_size(name, "wasm-strip", file_size(&wasm_path)?);
     }
     if !compilation_options.no_wasm_opt {
-        let status = Command::new("wasm-opt")
-            .arg(&wasm_path)
-            .arg("-o")
-            .arg(&wasm_path)
-            .arg("-Os")
-            .status()
-            .context("failed to run wasm-opt")?;
-        anyhow::ensure!(status.success(), "wasm-opt failed");
-
-        let size = file_size(&wasm_path)?;
-        log::debug!("after wasm-opt: {}kb", size / 1024);
+        run_wasm_tool(
+            Command::new("wasm-opt")
+                .arg(&wasm_path)
+                .arg("-o")
+                .arg(&wasm_path)
+                .arg("-Os"),
+        )?;
+        report_size(name, "wasm-opt", file_size(&wasm_path)?);
     }
 
     let mut wasm = std::fs::read(wasm_path).context("cannot read compiled wasm")?;
+    if let Some(metadata) = metadata {
+        metadata.embed(&mut wasm);
+    }
 
     if compilation_options.compress {
         wasm = miniz_oxide::deflate::compress_to_vec(&wasm, 6);
-        log::debug!("after compression: {}kb", wasm.len() / 1024);
+        report_size(name, "compression", wasm.len() as u64);
+    }
+
+    Ok(wasm)
+}
+
+fn run_wasm_tool(command: &mut Command) -> Result<(), anyhow::Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .with_context(|| format!("failed to run {}", program))
+        .context(ErrorCode::WasmToolFailed)?;
+    if !status.success() {
+        let error = anyhow::anyhow!("{} failed", program);
+        return Err(error.context(ErrorCode::WasmToolFailed));
     }
+    Ok(())
+}
+
+fn report_size(crate_: &str, stage: &str, size: u64) {
+    log::debug!("after {}: {}kb", stage, size / 1024);
+    report::emit(Event::WasmSize {
+        crate_,
+        stage,
+        size,
+    });
+}
+
+/// Reads a `.wasm` file, or a `.wasm.deflate` file generated with `--compress`.
+pub fn read_wasm_file(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    let content =
+        std::fs::read(path).with_context(|| format!("cannot read '{}'", path.display()))?;
 
-    Ok((fns, wasm))
+    if path.extension().is_some_and(|e| e == "deflate") {
+        return miniz_oxide::inflate::decompress_to_vec(&content)
+            .map_err(|e| anyhow::anyhow!("failed to decompress wasm: {:?}", e));
+    }
+    Ok(content)
 }
 
 fn rust_flags() -> String {
@@ -1,7 +1,9 @@
 use std::path::Path;
 use toml_edit::{value, Document};
 
-pub fn make_modifications(path: &Path) -> Result<Vec<ProcMacroFn>, anyhow::Error> {
+/// Turns the proc macro crate in `path` into one which can be compiled to wasm.
+/// The macros are exported under names mangled with the `namespace`, see [`export_name`].
+pub fn make_modifications(path: &Path, namespace: &str) -> Result<Vec<ProcMacroFn>, anyhow::Error> {
     let toml_path = path.join("Cargo.toml");
     let toml = std::fs::read_to_string(&toml_path)?;
     let new_toml = cargo_toml(&toml)?;
@@ -9,7 +11,7 @@ pub fn make_modifications(path: &Path) -> Result<Vec<ProcMacroFn>, anyhow::Error
 
     let lib_path = path.join("src").join("lib.rs");
     let lib = std::fs::read_to_string(&lib_path)?;
-    let (fns, new_lib) = librs(&lib)?;
+    let (fns, new_lib) = librs(&lib, namespace)?;
     std::fs::write(lib_path, new_lib)?;
 
     let lock = path.join("Cargo.lock");
@@ -84,8 +86,49 @@ pub fn cargo_toml(input: &str) -> Result<String, anyhow::Error> {
     Ok(manifest.to_string_in_original_order())
 }
 
+/// Turns a modified crate into a library to be used by another crate, instead of a wasm module.
+/// The patches and profiles are dropped since cargo only honors them in the root of a build.
+pub fn make_rlib(toml_path: &Path) -> Result<(), anyhow::Error> {
+    let mut manifest = crate::utils_toml::read(toml_path)?;
+    manifest["lib"]["crate-type"] = value(std::iter::once("rlib").collect::<toml_edit::Value>());
+    let table = manifest.as_table_mut();
+    table.remove("patch");
+    table.remove("profile");
+    table.remove("workspace");
+    std::fs::write(toml_path, manifest.to_string_in_original_order())?;
+    Ok(())
+}
+
+const EXPORT_PREFIX: &str = "__watt_";
+
+/// The name `macro_name` is exported under, `__watt_<length of namespace><namespace>_<macro>`.
+/// Unlike the plain name, it can't collide with symbols like `main` or `malloc`, or with macros of other crates in a bundle.
+pub fn export_name(namespace: &str, macro_name: &str) -> String {
+    format!(
+        "{}{}{}_{}",
+        EXPORT_PREFIX,
+        namespace.len(),
+        namespace,
+        macro_name
+    )
+}
+
+/// Splits an export created by [`export_name`] into the namespace and the name of the macro.
+pub fn demangle(export: &str) -> Option<(&str, &str)> {
+    let rest = export.strip_prefix(EXPORT_PREFIX)?;
+    let digits = rest.chars().take_while(char::is_ascii_digit).count();
+    let len: usize = rest[..digits].parse().ok()?;
+    let rest = &rest[digits..];
+    let namespace = rest.get(..len)?;
+    let macro_name = rest[len..].strip_prefix('_')?;
+    Some((namespace, macro_name))
+}
+
+#[derive(Clone)]
 pub struct ProcMacroFn {
     pub name: syn::Ident,
+    /// the name of the function in the wasm module
+    pub export: String,
     pub attrs: Vec<syn::Attribute>,
     pub kind: ProcMacroKind,
 }
@@ -100,20 +143,23 @@ pub enum ProcMacroKind {
 impl quote::ToTokens for ProcMacroFn {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         let ident = &self.name;
+        let export = &self.export;
         let mut new_fn: syn::ItemFn = match self.kind {
             ProcMacroKind::Macro | ProcMacroKind::ProcMacroHack => syn::parse_quote! {
                 pub fn #ident(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-                    MACRO.proc_macro(stringify!(#ident), input)
+                    __watt_record(#export, "macro", None, input, |_, input| __watt_macro().proc_macro(#export, input))
                 }
             },
             ProcMacroKind::Derive => syn::parse_quote! {
                 pub fn #ident(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-                    MACRO.proc_macro_derive(stringify!(#ident), input)
+                    __watt_record(#export, "derive", None, input, |_, input| __watt_macro().proc_macro_derive(#export, input))
                 }
             },
             ProcMacroKind::Attribute => syn::parse_quote! {
                 pub fn #ident(args: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-                    MACRO.proc_macro_attribute(stringify!(#ident), args, input)
+                    __watt_record(#export, "attribute", Some(args), input, |args, input| {
+                        __watt_macro().proc_macro_attribute(#export, args.unwrap(), input)
+                    })
                 }
             },
         };
@@ -122,13 +168,29 @@ impl quote::ToTokens for ProcMacroFn {
     }
 }
 
-pub fn librs(input: &str) -> Result<(Vec<ProcMacroFn>, String), anyhow::Error> {
+/// Turns the macros of a crate modified by [`make_modifications`] back into regular fns, to call them natively.
+/// A panic can't unwind out of an `extern "C"` fn, it would abort the compiler instead of being caught.
+pub fn make_native(path: &Path) -> Result<(), anyhow::Error> {
+    let lib_path = path.join("src").join("lib.rs");
+    let mut file = syn::parse_str::<syn::File>(&std::fs::read_to_string(&lib_path)?)?;
+    for item in &mut file.items {
+        if let syn::Item::Fn(f) = item {
+            if f.attrs.iter().any(|attr| attr.path.is_ident("export_name")) {
+                f.attrs.retain(|attr| !attr.path.is_ident("export_name"));
+                f.sig.abi = None;
+            }
+        }
+    }
+    std::fs::write(lib_path, quote::quote!(#file).to_string())?;
+    Ok(())
+}
+
+pub fn librs(input: &str, namespace: &str) -> Result<(Vec<ProcMacroFn>, String), anyhow::Error> {
     let mut file = syn::parse_str::<syn::File>(input)?;
     insert_allow_warnings(&mut file);
     remove_extern_procmacro(&mut file);
 
     let c_abi: syn::Abi = syn::parse_quote!(extern "C");
-    let no_mangle = parse_attributes(quote::quote!(#[no_mangle]))?;
 
     let mut fns = Vec::new();
     for (f, kind) in proc_macro_fns(&mut file) {
@@ -137,16 +199,20 @@ pub fn librs(input: &str) -> Result<(Vec<ProcMacroFn>, String), anyhow::Error> {
         //     ...
         // }
         // -->
-        // #[no_mangle]
+        // #[export_name = "__watt_8my_crate_my_macro"]
         // pub extern "C" fn my_macro(_input: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
         //    ...
         // }
-        let old_attrs = std::mem::replace(&mut f.attrs, no_mangle.clone());
+        let name = f.sig.ident.clone();
+        let export = export_name(namespace, &name.to_string());
+        let attrs = parse_attributes(quote::quote!(#[export_name = #export]))?;
+        let old_attrs = std::mem::replace(&mut f.attrs, attrs);
         f.sig.abi = Some(c_abi.clone());
         rename_tokenstream(&mut f.sig);
 
         fns.push(ProcMacroFn {
-            name: f.sig.ident.clone(),
+            name,
+            export,
             attrs: old_attrs,
             kind,
         });
@@ -162,10 +228,7 @@ fn proc_macro_fns(file: &mut syn::File) -> impl Iterator<Item = (&mut syn::ItemF
             syn::Item::Fn(item_fn) => Some(item_fn),
             _ => None,
         })
-        .filter_map(|item| match macro_kind(item) {
-            Some(kind) => Some((item, kind)),
-            _ => None,
-        })
+        .filter_map(|item| macro_kind(item).map(|kind| (item, kind)))
 }
 
 fn insert_allow_warnings(file: &mut syn::File) {
@@ -222,7 +285,7 @@ fn rename_tokenstream(sig: &mut syn::Signature) {
 
     for input in &mut sig.inputs {
         if let syn::FnArg::Typed(pat_type) = input {
-            pat_type.ty = Box::new(token_stream.clone());
+            *pat_type.ty = token_stream.clone();
         }
     }
 
@@ -242,3 +305,34 @@ pub fn parse_attributes(
     let AttrParser(attrs) = syn::parse2(token_stream)?;
     Ok(attrs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangle_export_name() {
+        let export = export_name("my_macros", "derive_debug");
+        assert_eq!(export, "__watt_9my_macros_derive_debug");
+        assert_eq!(demangle(&export), Some(("my_macros", "derive_debug")));
+    }
+
+    #[test]
+    fn demangle_namespace_with_digits_and_underscores() {
+        // bundles add the major version to the namespace
+        let export = export_name("serde_derive_1", "derive_serialize");
+        assert_eq!(
+            demangle(&export),
+            Some(("serde_derive_1", "derive_serialize"))
+        );
+    }
+
+    #[test]
+    fn demangle_foreign_exports() {
+        assert_eq!(demangle("derive_debug"), None);
+        assert_eq!(demangle("__watt_"), None);
+        assert_eq!(demangle("__watt_my_macros_derive"), None);
+        assert_eq!(demangle("__watt_20my_macros_derive"), None);
+        assert_eq!(demangle("__watt_9my_macrosXderive"), None);
+    }
+}
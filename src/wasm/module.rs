@@ -0,0 +1,156 @@
+use anyhow::Context;
+use wasmparser::{
+    ElementItem, ExternalKind, ImportSectionEntryType, Name, NameSectionReader, Operator, Payload,
+};
+
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    /// index into the function index space, if this is a function import
+    pub function: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct Function {
+    pub name: Option<String>,
+    /// size of the function body, 0 for imported functions
+    pub size: usize,
+    /// indices of all functions called directly from this function
+    pub calls: Vec<u32>,
+    pub calls_indirect: bool,
+}
+
+/// The parts of a wasm module cargo watt is interested in.
+/// Functions are stored in the function index space, so imported functions come first.
+#[derive(Default)]
+pub struct Module {
+    pub imports: Vec<Import>,
+    pub exports: Vec<(String, u32)>,
+    pub functions: Vec<Function>,
+    /// functions in the table, which can be called through `call_indirect`
+    pub table: Vec<u32>,
+}
+
+impl Module {
+    pub fn parse(wasm: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut module = Module::default();
+        let mut n_imported = 0;
+
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            match payload.context("failed to parse wasm module")? {
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import?;
+                        let function = match import.ty {
+                            ImportSectionEntryType::Function(_) => {
+                                module.functions.push(Function::default());
+                                n_imported += 1;
+                                Some(n_imported - 1)
+                            }
+                            _ => None,
+                        };
+                        module.imports.push(Import {
+                            module: import.module.to_string(),
+                            name: import.field.unwrap_or_default().to_string(),
+                            function,
+                        });
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if let ExternalKind::Function = export.kind {
+                            module
+                                .exports
+                                .push((export.field.to_string(), export.index));
+                        }
+                    }
+                }
+                Payload::ElementSection(reader) => {
+                    for element in reader {
+                        for item in element?.items.get_items_reader()? {
+                            if let ElementItem::Func(function) = item? {
+                                module.table.push(function);
+                            }
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let range = body.range();
+                    let mut function = Function {
+                        size: range.end - range.start,
+                        ..Function::default()
+                    };
+                    for op in body.get_operators_reader()? {
+                        match op? {
+                            Operator::Call { function_index } => {
+                                function.calls.push(function_index)
+                            }
+                            Operator::CallIndirect { .. } => function.calls_indirect = true,
+                            _ => {}
+                        }
+                    }
+                    module.functions.push(function);
+                }
+                Payload::CustomSection {
+                    name: "name",
+                    data,
+                    data_offset,
+                    ..
+                } => module.read_names(data, data_offset)?,
+                _ => {}
+            }
+        }
+
+        Ok(module)
+    }
+
+    fn read_names(&mut self, data: &[u8], offset: usize) -> Result<(), anyhow::Error> {
+        // the name section comes after the code section, so all functions are known at this point
+        for name in NameSectionReader::new(data, offset)? {
+            if let Name::Function(names) = name? {
+                let mut map = names.get_map()?;
+                for _ in 0..map.get_count() {
+                    let naming = map.read()?;
+                    if let Some(function) = self.functions.get_mut(naming.index as usize) {
+                        function.name = Some(naming.name.to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_names(&self) -> bool {
+        self.functions.iter().any(|f| f.name.is_some())
+    }
+
+    pub fn callers(&self, function: u32) -> impl Iterator<Item = &Function> {
+        self.functions
+            .iter()
+            .filter(move |f| f.calls.contains(&function))
+    }
+}
+
+/// Returns the name of the crate a function symbol belongs to.
+/// Handles both mangled (`_ZN4core3fmt5write17h..E`) and demangled (`core::fmt::write::h..`) names.
+pub fn crate_of(symbol: &str) -> Option<&str> {
+    if let Some(mangled) = symbol.strip_prefix("_ZN") {
+        let len_end = mangled.find(|c: char| !c.is_ascii_digit())?;
+        let len: usize = mangled[..len_end].parse().ok()?;
+        let segment = mangled.get(len_end..len_end + len)?;
+
+        // `<a::B as c::D>::f` is mangled as `_$LT$a..B$u20$as$u20$c..D$GT$`
+        return match segment.strip_prefix("_$LT$") {
+            Some(impl_block) => {
+                let impl_block = impl_block.trim_start_matches("$RF$");
+                impl_block.find("..").map(|end| &impl_block[..end])
+            }
+            None => Some(segment),
+        };
+    }
+
+    let path = symbol.trim_start_matches('<').trim_start_matches('&');
+    let end = path.find("::")?;
+    Some(&path[..end])
+}
@@ -0,0 +1,388 @@
+use super::module::{self, Module};
+use crate::utils::human_size;
+use std::{collections::HashMap, fmt::Write, path::Path, str::FromStr};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReportFormat {
+    Table,
+    Json,
+}
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(ReportFormat::Table),
+            "json" => Ok(ReportFormat::Json),
+            _ => anyhow::bail!("unknown report format '{}', expected 'table' or 'json'", s),
+        }
+    }
+}
+
+pub struct FunctionSize<'a> {
+    pub name: &'a str,
+    pub crate_: &'a str,
+    pub size: usize,
+    /// size of all functions which are only reachable through this one
+    pub retained: usize,
+    /// exported macros from which this function is reachable
+    pub kept_alive_by: Vec<&'a str>,
+}
+
+pub struct SizeReport<'a> {
+    pub total: usize,
+    pub crates: Vec<(&'a str, usize)>,
+    pub functions: Vec<FunctionSize<'a>>,
+}
+
+const UNKNOWN: &str = "<unknown>";
+
+// nodes of the call graph: the root (calling every export), the table (calling every function in it),
+// and then all functions
+const ROOT: usize = 0;
+const TABLE: usize = 1;
+const FIRST_FUNCTION: usize = 2;
+
+struct CallGraph {
+    edges: Vec<Vec<usize>>,
+}
+impl CallGraph {
+    fn new(module: &Module) -> Self {
+        let mut edges = vec![Vec::new(); FIRST_FUNCTION + module.functions.len()];
+        edges[ROOT] = module
+            .exports
+            .iter()
+            .map(|(_, f)| *f as usize + FIRST_FUNCTION)
+            .collect();
+        edges[TABLE] = module
+            .table
+            .iter()
+            .map(|f| *f as usize + FIRST_FUNCTION)
+            .collect();
+        for (i, function) in module.functions.iter().enumerate() {
+            let callees = &mut edges[i + FIRST_FUNCTION];
+            callees.extend(function.calls.iter().map(|f| *f as usize + FIRST_FUNCTION));
+            if function.calls_indirect {
+                callees.push(TABLE);
+            }
+        }
+        CallGraph { edges }
+    }
+
+    fn reachable_from(&self, start: usize) -> Vec<bool> {
+        let mut visited = vec![false; self.edges.len()];
+        let mut stack = vec![start];
+        while let Some(node) = stack.pop() {
+            if std::mem::replace(&mut visited[node], true) {
+                continue;
+            }
+            stack.extend(self.edges[node].iter().filter(|n| !visited[**n]));
+        }
+        visited
+    }
+
+    fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.edges.len()];
+        let mut postorder = Vec::new();
+        let mut stack = vec![(ROOT, 0)];
+        visited[ROOT] = true;
+        while let Some((node, next_edge)) = stack.pop() {
+            match self.edges[node].get(next_edge) {
+                Some(&child) => {
+                    stack.push((node, next_edge + 1));
+                    if !visited[child] {
+                        visited[child] = true;
+                        stack.push((child, 0));
+                    }
+                }
+                None => postorder.push(node),
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    /// Computes the immediate dominator of every node reachable from the root
+    /// (see "A Simple, Fast Dominance Algorithm" by Cooper, Harvey and Kennedy).
+    fn immediate_dominators(&self) -> Vec<Option<usize>> {
+        let rpo = self.reverse_postorder();
+        let mut order = vec![usize::MAX; self.edges.len()];
+        for (i, node) in rpo.iter().enumerate() {
+            order[*node] = i;
+        }
+        let mut predecessors = vec![Vec::new(); self.edges.len()];
+        for &node in &rpo {
+            for &child in &self.edges[node] {
+                predecessors[child].push(node);
+            }
+        }
+
+        let mut idom = vec![None; self.edges.len()];
+        idom[ROOT] = Some(ROOT);
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while order[a] > order[b] {
+                    a = idom[a].unwrap();
+                }
+                while order[b] > order[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in rpo.iter().skip(1) {
+                let new_idom = predecessors[node]
+                    .iter()
+                    .filter(|p| idom[**p].is_some())
+                    .fold(None, |acc, &p| match acc {
+                        None => Some(p),
+                        Some(acc) => Some(intersect(&idom, acc, p)),
+                    });
+                if new_idom.is_some() && idom[node] != new_idom {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+}
+
+impl<'a> SizeReport<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        let size_of = |node: usize| {
+            node.checked_sub(FIRST_FUNCTION)
+                .map_or(0, |f| module.functions[f].size)
+        };
+        let graph = CallGraph::new(module);
+
+        // every node adds its retained size to its immediate dominator, so process them bottom up
+        let idom = graph.immediate_dominators();
+        let mut retained: Vec<usize> = (0..graph.edges.len()).map(size_of).collect();
+        for node in graph.reverse_postorder().into_iter().rev() {
+            match idom[node] {
+                Some(dominator) if dominator != node => retained[dominator] += retained[node],
+                _ => {}
+            }
+        }
+
+        let mut kept_alive_by = vec![Vec::new(); module.functions.len()];
+        for (name, function) in &module.exports {
+            let reachable = graph.reachable_from(*function as usize + FIRST_FUNCTION);
+            for (i, _) in reachable[FIRST_FUNCTION..]
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| **r)
+            {
+                // the name of the macro rather than the mangled export
+                let name = match super::modifications::demangle(name) {
+                    Some((_, macro_name)) => macro_name,
+                    None => name.as_str(),
+                };
+                kept_alive_by[i].push(name);
+            }
+        }
+
+        let mut crates = HashMap::new();
+        let mut functions = Vec::new();
+        for (i, (function, kept_alive_by)) in module.functions.iter().zip(kept_alive_by).enumerate()
+        {
+            // imported functions
+            if function.size == 0 {
+                continue;
+            }
+            let name = function.name.as_deref().unwrap_or(UNKNOWN);
+            let crate_ = module::crate_of(name).unwrap_or(UNKNOWN);
+            *crates.entry(crate_).or_insert(0) += function.size;
+            functions.push(FunctionSize {
+                name,
+                crate_,
+                size: function.size,
+                retained: retained[i + FIRST_FUNCTION],
+                kept_alive_by,
+            });
+        }
+
+        let mut crates: Vec<_> = crates.into_iter().collect();
+        crates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        functions.sort_by(|a, b| b.retained.cmp(&a.retained).then(b.size.cmp(&a.size)));
+
+        SizeReport {
+            total: module.functions.iter().map(|f| f.size).sum(),
+            crates,
+            functions,
+        }
+    }
+
+    fn percent(&self, size: usize) -> f32 {
+        size as f32 / self.total.max(1) as f32 * 100.0
+    }
+
+    /// The report as a table, built as a whole so that it can be written in one piece.
+    pub fn table(&self, top: usize) -> String {
+        let mut table = String::new();
+        let _ = writeln!(table, "code size: {}", human_size(self.total as u64));
+        let _ = writeln!(table);
+        let _ = writeln!(table, "{:<40} {:>10} {:>7}", "crate", "size", "%");
+        for (crate_, size) in self.crates.iter().take(top) {
+            let _ = writeln!(
+                table,
+                "{:<40} {:>10} {:>6.1}%",
+                crate_,
+                human_size(*size as u64),
+                self.percent(*size)
+            );
+        }
+        let _ = writeln!(table);
+        let _ = writeln!(
+            table,
+            "{:<60} {:>10} {:>10}  kept alive by",
+            "function", "shallow", "retained"
+        );
+        for function in self.functions.iter().take(top) {
+            let _ = writeln!(
+                table,
+                "{:<60} {:>10} {:>10}  {}",
+                truncate(function.name, 60),
+                function.size,
+                function.retained,
+                function.kept_alive_by.join(", ")
+            );
+        }
+        table
+    }
+
+    pub fn to_json(&self, top: usize) -> serde_json::Value {
+        let crates: Vec<_> = self
+            .crates
+            .iter()
+            .map(|(name, size)| serde_json::json!({ "name": name, "size": size }))
+            .collect();
+        let functions: Vec<_> = self
+            .functions
+            .iter()
+            .take(top)
+            .map(|f| {
+                serde_json::json!({
+                    "name": f.name,
+                    "crate": f.crate_,
+                    "size": f.size,
+                    "retained": f.retained,
+                    "kept_alive_by": f.kept_alive_by,
+                })
+            })
+            .collect();
+        serde_json::json!({ "total": self.total, "crates": crates, "functions": functions })
+    }
+
+    pub fn render(&self, format: ReportFormat, top: usize) -> String {
+        match format {
+            ReportFormat::Table => self.table(top),
+            ReportFormat::Json => format!("{}\n", self.to_json(top)),
+        }
+    }
+}
+
+fn truncate(s: &str, len: usize) -> &str {
+    match s.char_indices().nth(len) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+pub fn size(wasm_file: &Path, format: ReportFormat, top: usize) -> Result<(), anyhow::Error> {
+    let wasm = super::read_wasm_file(wasm_file)?;
+    let module = Module::parse(&wasm)?;
+    if !module.has_names() {
+        log::warn!("the wasm file has no name section, compile with --size-report to get the report before wasm-strip");
+    }
+
+    print!("{}", SizeReport::new(&module).render(format, top));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use module::Function;
+
+    fn function(size: usize, calls: &[u32]) -> Function {
+        Function {
+            name: Some(format!("f{}", size)),
+            size,
+            calls: calls.to_vec(),
+            calls_indirect: false,
+        }
+    }
+
+    #[test]
+    fn immediate_dominators() {
+        // ROOT -> 2 -> {3, 4} -> 5 -> 6 -> 5
+        let graph = CallGraph {
+            edges: vec![
+                vec![2],
+                vec![],
+                vec![3, 4],
+                vec![5],
+                vec![5],
+                vec![6],
+                vec![5],
+                // unreachable
+                vec![2],
+            ],
+        };
+        assert_eq!(
+            graph.immediate_dominators(),
+            [
+                Some(ROOT),
+                None,
+                Some(ROOT),
+                Some(2),
+                Some(2),
+                Some(2),
+                Some(5),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn retained_sizes() {
+        // `a` calls 10 and 20, which share 30, which alone calls 40.
+        // 20 calls through the table, which holds 50.
+        let mut functions = vec![
+            function(1, &[1, 2]),
+            function(10, &[3]),
+            function(20, &[3]),
+            function(30, &[4]),
+            function(40, &[]),
+            function(50, &[]),
+        ];
+        functions[2].calls_indirect = true;
+        let module = Module {
+            exports: vec![("__watt_1m_a".to_string(), 0)],
+            functions,
+            table: vec![5],
+            ..Module::default()
+        };
+
+        let report = SizeReport::new(&module);
+        let retained: HashMap<usize, usize> = report
+            .functions
+            .iter()
+            .map(|f| (f.size, f.retained))
+            .collect();
+        assert_eq!(retained[&1], 151);
+        assert_eq!(retained[&10], 10);
+        assert_eq!(retained[&20], 70);
+        assert_eq!(retained[&30], 70);
+        assert_eq!(retained[&40], 40);
+        assert_eq!(retained[&50], 50);
+        assert!(report.functions.iter().all(|f| f.kept_alive_by == ["a"]));
+        assert_eq!(report.total, 151);
+    }
+}
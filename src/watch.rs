@@ -0,0 +1,188 @@
+//! `cargo watt watch` rebuilds the wasm file of a macro crate whenever its sources change.
+//! Shims load the file in `$WATT_OVERRIDE_<CRATE>` instead of their own (see `build::shim_librs`),
+//! so a consumer picks up the changes without regenerating the shim.
+
+use crate::{
+    build,
+    patch::store,
+    utils,
+    wasm::{self, TargetDir},
+    CompilationOptions,
+};
+use anyhow::Context;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches the crate in `directory` and writes its wasm file to `out_file` after every change.
+/// The files in `touch` are touched afterwards, so that cargo expands their crates again.
+pub fn watch(
+    directory: &Path,
+    out_file: Option<PathBuf>,
+    compilation_options: &CompilationOptions,
+    touch: &[PathBuf],
+) -> Result<(), anyhow::Error> {
+    anyhow::ensure!(
+        !compilation_options.compress,
+        "shims load the override as it is, so it can't be compressed"
+    );
+    let manifest = utils::parse_validate_toml(&directory.join("Cargo.toml"))?;
+    let name = manifest["package"]["name"].as_str().unwrap().to_string();
+
+    let directory = &directory
+        .canonicalize()
+        .with_context(|| format!("cannot find '{}'", directory.display()))?;
+    let out_file = match out_file {
+        Some(out_file) => resolve(&out_file)?,
+        None => directory
+            .join("target/cargo-watt")
+            .join(build::wasm_file_name(&name, false)),
+    };
+    // writing the wasm file and touching files mustn't trigger the next rebuild, in case they are inside the crate
+    let mut ignored = vec![out_file.clone(), out_file.with_extension("wasm.tmp")];
+    for path in touch {
+        ignored.push(resolve(path)?);
+    }
+    // the crate is rebuilt in a copy, which would start from scratch without a target directory of its own
+    let target_dir = store::cache_dir()
+        .map(|dir| dir.join("watch").join(&name))
+        .unwrap_or_else(|| directory.join("target/cargo-watt/watch"));
+    let jobs = std::thread::available_parallelism().map_or(1, usize::from);
+
+    log::info!(
+        "watching '{}', build the consumer with {}={}",
+        directory.display(),
+        build::override_var(&name),
+        out_file.display()
+    );
+    let mut last = None;
+    loop {
+        let current = fingerprint(directory, &ignored)?;
+        if last.as_ref() != Some(&current) {
+            last = Some(current);
+            let target_dir = TargetDir {
+                path: &target_dir,
+                jobs,
+            };
+            // a broken state of the crate is expected while editing it, so this keeps watching
+            match rebuild(
+                directory,
+                &out_file,
+                compilation_options,
+                &target_dir,
+                touch,
+            ) {
+                Ok(()) => log::info!("updated '{}'", out_file.display()),
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn rebuild(
+    directory: &Path,
+    out_file: &Path,
+    compilation_options: &CompilationOptions,
+    target_dir: &TargetDir,
+    touch: &[PathBuf],
+) -> Result<(), anyhow::Error> {
+    let scratch = utils::Tempdir::new().context("failed to create temporary directory")?;
+    utils::copy_sources(directory, &scratch, &directory.join("target"))?;
+    let manifest = utils::parse_validate_toml(&scratch.join("Cargo.toml"))?;
+    let (_, wasm) = wasm::compile(
+        &scratch,
+        &manifest,
+        compilation_options,
+        Some(target_dir),
+        false,
+    )?;
+
+    if let Some(parent) = out_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // the consumer may be expanding the macros right now, so it must not see a partially written file
+    let tmp_file = out_file.with_extension("wasm.tmp");
+    std::fs::write(&tmp_file, wasm)?;
+    std::fs::rename(&tmp_file, out_file)?;
+
+    for path in touch {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .with_context(|| format!("cannot touch '{}'", path.display()))?;
+    }
+    Ok(())
+}
+
+/// `path` as an absolute path with the symlinks of its directory resolved, like the paths [`fingerprint`] finds.
+/// The file itself doesn't need to exist yet.
+fn resolve(path: &Path) -> Result<PathBuf, anyhow::Error> {
+    let path = std::env::current_dir()?.join(path);
+    let dir = path.parent().unwrap_or(&path);
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("cannot find '{}'", dir.display()))?;
+    Ok(match path.file_name() {
+        Some(file_name) => dir.join(file_name),
+        None => dir,
+    })
+}
+
+/// The modification times of the files of the crate in the canonical `directory`,
+/// leaving out the target directory and the `ignored` files.
+fn fingerprint(
+    directory: &Path,
+    ignored: &[PathBuf],
+) -> Result<Vec<(PathBuf, SystemTime)>, anyhow::Error> {
+    let target_dir = directory.join("target");
+    let entries = walkdir::WalkDir::new(directory)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.path() != target_dir
+                && entry.file_name() != ".git"
+                && !ignored.iter().any(|path| path == entry.path())
+        });
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push((entry.path().to_path_buf(), entry.metadata()?.modified()?));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_leaves_out_outputs() {
+        let dir = utils::Tempdir::new().unwrap();
+        let dir = dir.canonicalize().unwrap();
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("target/cargo-watt")).unwrap();
+        for file in [
+            "Cargo.toml",
+            "src/lib.rs",
+            "my_macros.wasm",
+            "target/cargo-watt/a.wasm",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let ignored = [dir.join("my_macros.wasm"), dir.join("my_macros.wasm.tmp")];
+        let files: Vec<PathBuf> = fingerprint(&dir, &ignored)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(files, [dir.join("Cargo.toml"), dir.join("src/lib.rs")]);
+    }
+}